@@ -1,72 +1,656 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::Redirect,
-    routing::{get, post},
+    async_trait,
+    body::Body,
+    extract::{
+        ws::{close_code, CloseFrame},
+        ConnectInfo, FromRequestParts, MatchedPath, Path, State,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN},
+        request::Parts,
+        Method, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
     Extension, Json, Router,
 };
-use shuttle_axum::ShuttleAxum;
+use subtle::ConstantTimeEq;
 use tokio::sync::{
     broadcast::{channel, Sender},
-    RwLock,
+    Mutex, RwLock,
 };
-use tower_http::services::{ServeDir, ServeFile};
-use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    trace::TraceLayer,
+};
+use tower_sessions::{cookie::SameSite, Session, SessionManagerLayer};
+use tracing::{debug, error, field::Empty, info, info_span, warn, Instrument, Span};
+
+use config::Config;
+#[cfg(not(feature = "shuttle"))]
+use config::LogFormat;
+use metrics::Metrics;
+use rate_limit::{Chat, Clears, Pixels, RateLimits, RoomCreation, Throttle};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "shuttle")]
+use storage::PostgresStorage;
+#[cfg(not(feature = "shuttle"))]
+use storage::SqliteStorage;
+use storage::{unix_now, MemoryStorage, RoomSnapshot, SessionStorage, Storage};
+use ws::{CanvasEvent, CloseSockets};
 
-use common::{ChatMessage, GameState, JoinLobbyPost, Player, SessionPlayer, SetPixelPost};
+use common::{
+    normalize_room_code, random_room_code, ChatMessage, Color, CreateLobbyPost, DrawCanvas,
+    FillPost, FinishedGame, GamePhase, GameState, JoinLobbyPost, LayerPost, Player, RoomListing,
+    RoomSettings, SessionPlayer, SetPixelPost, StrokePost, Tick, ROOM_CODE_LENGTH,
+};
 
+#[cfg(feature = "shuttle")]
 #[shuttle_runtime::main]
-async fn axum() -> ShuttleAxum {
-    let app = build_app().await?;
+async fn axum(#[shuttle_shared_db::Postgres] pool: sqlx::PgPool) -> shuttle_axum::ShuttleAxum {
+    let mut config = Config::load()?;
+    // Shuttle always serves over HTTPS
+    config.security.secure_cookies.get_or_insert(true);
+    let storage = PostgresStorage::new(pool).await?;
+    let (app, state) = build_app(Arc::new(storage), config).await?;
+    // Listening for the signals replaces their default handling, and the Shuttle runtime
+    // has none of its own, so the process has to exit once the rooms are saved
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        state.shutdown().await;
+        std::process::exit(0);
+    });
     Ok(app.into())
 }
 
-#[derive(Default)]
+/// Standalone server for self-hosting
+#[cfg(not(feature = "shuttle"))]
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Config::load()?;
+    let filter = tracing_subscriber::EnvFilter::try_new(&config.logging.level)?;
+    match config.logging.format {
+        LogFormat::Pretty => tracing_subscriber::fmt().with_env_filter(filter).init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init(),
+    }
+
+    let storage = SqliteStorage::open(&config.storage.database_path)?;
+    let addr = config.network.bind_address;
+    let (app, state) = build_app(Arc::new(storage), config).await?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        state.shutdown().await;
+    })
+    .await?;
+    Ok(())
+}
+
+const ROOM_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+const ROOM_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+
 pub struct AppState {
-    pub rooms: RwLock<HashMap<u32, Arc<RoomState>>>,
+    pub rooms: RwLock<HashMap<String, Arc<RoomState>>>,
+    /// Wrong room codes and passwords per client, to stop codes from being enumerated
+    pub failed_joins: Mutex<HashMap<IpAddr, (u32, Instant)>>,
+    pub config: Config,
+    pub metrics: Metrics,
+    pub rate_limits: RateLimits,
+    pub storage: Arc<dyn Storage>,
+    /// Set when the server is going down, so it stops reporting ready
+    pub shutting_down: AtomicBool,
+}
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStorage::default()), Config::default())
+    }
+}
+impl AppState {
+    pub fn new(storage: Arc<dyn Storage>, config: Config) -> Self {
+        Self {
+            rooms: Default::default(),
+            failed_joins: Default::default(),
+            rate_limits: RateLimits::new(&config.limits),
+            config,
+            metrics: Metrics::default(),
+            storage,
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+    /// Creates a room and starts its timer, returns the room code
+    async fn create_room(
+        self: &Arc<Self>,
+        settings: RoomSettings,
+        host: String,
+        password: Option<String>,
+    ) -> String {
+        let password_hash = match password {
+            Some(password) => Some(hash_password(password).await),
+            None => None,
+        };
+        let mut rooms = self.rooms.write().await;
+        // Collisions are rare at this length, growing it only guards against a full code space
+        let code = (0..)
+            .map(|attempt| random_room_code(ROOM_CODE_LENGTH + attempt / 64 * 2))
+            .find(|code| !rooms.contains_key(code))
+            .unwrap();
+        let room = Arc::new(RoomState::new(
+            code.clone(),
+            password_hash,
+            GameState::new(settings, host),
+            self.config.network.channel_capacity,
+        ));
+        room.spawn_timer(self);
+        rooms.insert(code.clone(), room.clone());
+        drop(rooms);
+        if let Err(e) = self.storage.save_room(room.snapshot().await).await {
+            error!(room_id = %code, "Failed to save room: {e}");
+        }
+        info!(room_id = %code, event = "room_created", "Room created");
+        code
+    }
+    /// Brings back the rooms that were running before a restart
+    async fn restore_rooms(self: &Arc<Self>) -> Result<()> {
+        let snapshots = self.storage.load_rooms().await?;
+        let mut rooms = self.rooms.write().await;
+        for snapshot in snapshots {
+            let room = Arc::new(RoomState::new(
+                snapshot.room_id,
+                snapshot.password_hash,
+                snapshot.game_state,
+                self.config.network.channel_capacity,
+            ));
+            room.spawn_timer(self);
+            rooms.insert(room.room_id.clone(), room);
+        }
+        Ok(())
+    }
+    /// Deletes the snapshot of a room that was taken out of `rooms`
+    async fn forget_room(&self, room: &RoomState) {
+        room.closed.store(true, Ordering::SeqCst);
+        if let Err(e) = self.storage.delete_room(&room.room_id).await {
+            error!(room_id = %room.room_id, "Failed to delete room: {e}");
+        }
+    }
+    async fn save_all_rooms(&self) {
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        for room in rooms {
+            if let Err(e) = self.storage.save_room(room.snapshot().await).await {
+                error!(room_id = %room.room_id, "Failed to save room: {e}");
+            }
+            // Closed while we were saving, its delete may have gone through first
+            if room.closed.load(Ordering::SeqCst) {
+                self.forget_room(&room).await;
+            }
+        }
+    }
+    /// Saves every room now and then, so a crash loses as little as possible
+    async fn save_rooms(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(ROOM_SNAPSHOT_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.save_all_rooms().await;
+        }
+    }
+    /// Tells everyone the server is restarting, closes their sockets so they start
+    /// reconnecting, and saves the rooms to be restored on the next start
+    pub async fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let rooms: Vec<_> = self.rooms.read().await.values().cloned().collect();
+        for room in &rooms {
+            room.system_message("The server is restarting, hang on...".into());
+            room.close_sockets(None, close_code::RESTART, "Server restarting");
+        }
+        self.save_all_rooms().await;
+        // Give the sockets a moment to send their close frames
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    /// Takes a player out of their room, kicked players may not come back.
+    /// The room is closed when the last player leaves
+    async fn remove_player(&self, room_id: &str, username: &str, kick: bool) {
+        let mut rooms = self.rooms.write().await;
+        let Some(room) = rooms.get(room_id).cloned() else {
+            return;
+        };
+        let mut gs = room.game_state.write().await;
+        let advance = if kick {
+            gs.kick(username)
+        } else {
+            gs.remove_player(Player::new(username.into()))
+        };
+        if gs.players.is_empty() {
+            rooms.remove(room_id);
+            drop(gs);
+            drop(rooms);
+            self.forget_room(&room).await;
+            return;
+        }
+        if advance {
+            gs.new_round();
+            if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+                debug!("No receivers");
+            }
+        }
+        if room.game_channel.send(true).is_err() {
+            debug!("No receivers");
+        }
+    }
+    async fn join_locked_out(&self, ip: IpAddr) -> bool {
+        let mut failed = self.failed_joins.lock().await;
+        match failed.get(&ip) {
+            Some((_, since)) if since.elapsed() > self.config.security.failed_join_window() => {
+                failed.remove(&ip);
+                false
+            }
+            Some((count, _)) => *count >= self.config.security.max_failed_joins,
+            None => false,
+        }
+    }
+    async fn record_failed_join(&self, ip: IpAddr) {
+        let mut failed = self.failed_joins.lock().await;
+        failed.entry(ip).or_insert((0, Instant::now())).0 += 1;
+    }
+    /// Removes rooms that nobody has been connected to for a while, and expired sessions
+    async fn sweep_idle_rooms(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(ROOM_SWEEP_INTERVAL);
+        let timeout = self.config.limits.room_idle_timeout();
+        loop {
+            interval.tick().await;
+            let mut idle = vec![];
+            self.rooms.write().await.retain(|_, room| {
+                let keep = !room.is_idle(timeout);
+                if !keep {
+                    idle.push(room.clone());
+                }
+                keep
+            });
+            for room in idle {
+                info!(room_id = %room.room_id, event = "room_removed", "Removed idle room");
+                self.forget_room(&room).await;
+            }
+            self.rate_limits.sweep();
+            let window = self.config.security.failed_join_window();
+            self.failed_joins
+                .lock()
+                .await
+                .retain(|_, (_, since)| since.elapsed() <= window);
+            if let Err(e) = self.storage.delete_expired_sessions().await {
+                error!("Failed to delete expired sessions: {e}");
+            }
+        }
+    }
+}
+
+/// The client's address. `X-Forwarded-For` is only believed when the connection comes from
+/// one of the trusted proxies, or has no peer address because Shuttle's proxy serves us
+pub struct ClientIp(pub IpAddr);
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let trusted = &state.config.network.trusted_proxies;
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if peer.is_some_and(|ip| !trusted.contains(&ip)) {
+            return Ok(Self(peer.unwrap()));
+        }
+        // Proxies append the address they got the request from, so the last entry that
+        // isn't one of ours is the client. Anything before it may be made up
+        let forwarded = parts
+            .headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        let client = forwarded.into_iter().rev().find(|ip| !trusted.contains(ip));
+        Ok(Self(
+            client.or(peer).unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        ))
+    }
+}
+
+/// Requests carrying the configured admin token as `Authorization: Bearer <token>`
+pub struct Admin;
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppState>,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(token) = &state.config.security.admin_token else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        let given = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        // Compared in constant time, so the token can't be guessed from response times
+        match given {
+            Some(given) if bool::from(given.as_bytes().ct_eq(token.as_bytes())) => Ok(Self),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Argon2 is slow on purpose, so it runs on the blocking pool instead of holding up the executor
+async fn hash_password(password: String) -> String {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    })
+    .await
+    .unwrap()
 }
 
 pub struct RoomState {
     pub room_id: String,
+    pub password_hash: Option<String>,
     pub game_state: RwLock<GameState>,
     pub game_channel: Sender<bool>,
-    pub canvas_channel: Sender<bool>,
+    pub canvas_channel: Sender<CanvasEvent>,
     pub chat_channel: Sender<ChatMessage>,
+    pub close_channel: Sender<CloseSockets>,
+    /// Open WebSockets, and when the last one closed
+    pub connections: AtomicUsize,
+    pub idle_since: std::sync::Mutex<Instant>,
+    /// Set once the room is gone, so a snapshot being saved doesn't bring it back
+    pub closed: AtomicBool,
 }
 impl RoomState {
-    fn new(room_id: String) -> Self {
+    fn new(
+        room_id: String,
+        password_hash: Option<String>,
+        game_state: GameState,
+        channel_capacity: usize,
+    ) -> Self {
         Self {
             room_id,
-            game_state: RwLock::new(GameState::new()),
-            game_channel: channel(128).0,
-            canvas_channel: channel(128).0,
-            chat_channel: channel(128).0,
+            password_hash,
+            game_state: RwLock::new(game_state),
+            game_channel: channel(channel_capacity).0,
+            canvas_channel: channel(channel_capacity).0,
+            chat_channel: channel(channel_capacity).0,
+            close_channel: channel(channel_capacity).0,
+            connections: AtomicUsize::new(0),
+            idle_since: std::sync::Mutex::new(Instant::now()),
+            closed: AtomicBool::new(false),
+        }
+    }
+    /// Sends the pixels the last edit of `canvas` changed to the canvas sockets, or has them
+    /// send the whole canvas if more changed. Called with the room still locked, so the
+    /// sockets get the changes in the order they were made
+    fn send_canvas_changes(&self, canvas: &mut DrawCanvas) {
+        let event = match canvas.take_delta() {
+            Some(delta) if delta.layers.is_empty() => return,
+            Some(delta) => CanvasEvent::Pixels(Arc::new(delta)),
+            None => CanvasEvent::Resync,
+        };
+        if self.canvas_channel.send(event).is_err() {
+            debug!("No receivers");
+        }
+    }
+    fn system_message(&self, text: String) {
+        if self
+            .chat_channel
+            .send(ChatMessage {
+                username: "SYSTEM".into(),
+                text,
+            })
+            .is_err()
+        {
+            debug!("No receivers");
+        }
+    }
+    /// Closes the sockets of one player, or everyone's
+    fn close_sockets(&self, username: Option<String>, code: u16, reason: &'static str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.into(),
+        };
+        if self
+            .close_channel
+            .send(CloseSockets { username, frame })
+            .is_err()
+        {
+            debug!("No receivers");
+        }
+    }
+    async fn snapshot(&self) -> RoomSnapshot {
+        RoomSnapshot {
+            room_id: self.room_id.clone(),
+            password_hash: self.password_hash.clone(),
+            game_state: self.game_state.read().await.clone(),
+        }
+    }
+    fn is_idle(&self, timeout: Duration) -> bool {
+        self.connections.load(Ordering::SeqCst) == 0
+            && self.idle_since.lock().unwrap().elapsed() > timeout
+    }
+    async fn check_password(&self, password: Option<String>) -> bool {
+        let Some(hash) = self.password_hash.clone() else {
+            return true;
+        };
+        let Some(password) = password else {
+            return false;
+        };
+        tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        })
+        .await
+        .unwrap()
+    }
+    /// Drives the turn timer and hints once per second, for as long as the room exists.
+    /// Also records the game in the history when it finishes
+    fn spawn_timer(self: &Arc<Self>, state: &Arc<AppState>) {
+        let room = Arc::downgrade(self);
+        let state = Arc::downgrade(state);
+        let span = info_span!("room", room_id = %self.room_id);
+        tokio::spawn(
+            async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            let mut was_playing = false;
+            loop {
+                interval.tick().await;
+                let (Some(room), Some(state)) = (room.upgrade(), state.upgrade()) else {
+                    return;
+                };
+                let (timed, tick, finished) = {
+                    let mut gs = room.game_state.write().await;
+                    let timed = gs.time_left().is_some();
+                    let tick = gs.tick();
+                    let finished = (was_playing && gs.phase == GamePhase::Finished).then(|| {
+                        FinishedGame {
+                            room_id: room.room_id.clone(),
+                            players: gs.players.iter().map(|p| p.username.clone()).collect(),
+                            rounds: gs.round.saturating_sub(1),
+                            finished_at: unix_now() as u64,
+                        }
+                    });
+                    was_playing = gs.phase == GamePhase::Playing;
+                    (timed, tick, finished)
+                };
+                // Saved without holding the room, the write may take a while
+                if let Some(game) = finished {
+                    info!(event = "game_finished", "Game finished");
+                    if let Err(e) = state.storage.save_finished_game(game).await {
+                        error!("Failed to save finished game: {e}");
+                    }
+                }
+                match tick {
+                    Tick::Continue => {
+                        if timed && room.game_channel.send(true).is_err() {
+                            debug!("No receivers");
+                        }
+                    }
+                    Tick::Hint => {
+                        if room.game_channel.send(true).is_err() {
+                            debug!("No receivers");
+                        }
+                    }
+                    Tick::TurnOver(word) => {
+                        debug!(event = "turn_over", word, "Turn over");
+                        state.metrics.rounds.inc();
+                        if room.game_channel.send(true).is_err() {
+                            debug!("No receivers");
+                        }
+                        if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+                            debug!("No receivers");
+                        }
+                        if room
+                            .chat_channel
+                            .send(ChatMessage {
+                                username: "SYSTEM".into(),
+                                text: format!("Time's up! The word was {word}."),
+                            })
+                            .is_err()
+                        {
+                            debug!("No receivers");
+                        }
+                    }
+                    Tick::Skipped {
+                        username,
+                        word,
+                        removed,
+                    } => {
+                        info!(
+                            event = "turn_skipped",
+                            player = username,
+                            removed,
+                            "Skipped idle drawer"
+                        );
+                        state.metrics.rounds.inc();
+                        if room.game_channel.send(true).is_err() {
+                            debug!("No receivers");
+                        }
+                        if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+                            debug!("No receivers");
+                        }
+                        let text = if removed {
+                            format!("{username} was removed for being away. The word was {word}.")
+                        } else {
+                            format!("{username} seems to be away, skipping their turn. The word was {word}.")
+                        };
+                        if room
+                            .chat_channel
+                            .send(ChatMessage {
+                                username: "SYSTEM".into(),
+                                text,
+                            })
+                            .is_err()
+                        {
+                            debug!("No receivers");
+                        }
+                    }
+                    Tick::Abandoned { username } => {
+                        info!(
+                            event = "room_removed",
+                            player = username,
+                            "Removed room after its last player went away"
+                        );
+                        state.rooms.write().await.remove(&room.room_id);
+                        state.forget_room(&room).await;
+                        return;
+                    }
+                }
+            }
         }
+        .instrument(span),
+        );
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.unwrap();
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
 }
 
-pub async fn build_app() -> Result<Router> {
+/// The app's routes, and its state for shutting down
+pub async fn build_app(
+    storage: Arc<dyn Storage>,
+    config: Config,
+) -> Result<(Router, Arc<AppState>)> {
     // Cookie sessions
-    let session_store = MemoryStore::default();
-    let session_layer = SessionManagerLayer::new(session_store).with_secure(false);
+    let session_store = SessionStorage(storage.clone());
+    let session_layer = SessionManagerLayer::new(session_store)
+        .with_secure(config.security.secure_cookies.unwrap_or(false))
+        .with_same_site(SameSite::Strict);
+    let static_dir = config.network.static_dir.clone();
 
     // Connections, state, and channels for the app
-    let state: Arc<AppState> = Arc::new(Default::default());
+    let state = Arc::new(AppState::new(storage, config));
+    state.restore_rooms().await?;
+    tokio::spawn(state.clone().sweep_idle_rooms());
+    tokio::spawn(state.clone().save_rooms());
     let app = Router::new()
         .route(
             "/ws/:room_id/canvas",
-            get(|w, s, p, e| ws::ws_handler(w, s, p, e, ws::WsStreamType::Canvas)),
+            get(|w, s, i, p, e| ws::ws_handler(w, s, i, p, e, ws::WsStreamType::Canvas)),
         )
         .route(
             "/ws/:room_id/game",
-            get(|w, s, p, e| ws::ws_handler(w, s, p, e, ws::WsStreamType::Game)),
+            get(|w, s, i, p, e| ws::ws_handler(w, s, i, p, e, ws::WsStreamType::Game)),
         )
         .route(
             "/ws/:room_id/chat",
-            get(|w, s, p, e| ws::ws_handler(w, s, p, e, ws::WsStreamType::Chat)),
+            get(|w, s, i, p, e| ws::ws_handler(w, s, i, p, e, ws::WsStreamType::Chat)),
         )
         .layer(Extension(state.clone()))
         .nest(
@@ -74,57 +658,138 @@ pub async fn build_app() -> Result<Router> {
             Router::new()
                 .route("/create_lobby", post(create_lobby))
                 .route("/join_lobby/:room_id", post(join_lobby))
-                .route("/leave_lobby", get(leave_lobby))
-                .route("/player", get(get_player_name))
+                .route("/quick_play", post(quick_play))
+                .route("/rooms", get(list_rooms))
+                .route("/history", get(history))
+                .route("/leave_lobby", post(leave_lobby))
+                .route("/player", get(get_player))
                 .route("/set_pixel", post(set_pixel_handler))
-                .route("/clear_canvas", get(clear_canvas_handler))
-                .route("/chat", post(chat_handler)),
+                .route("/stroke", post(stroke_handler))
+                .route("/fill", post(fill_handler))
+                .route("/undo", post(undo_handler))
+                .route("/redo", post(redo_handler))
+                .route("/layers", post(layers_handler))
+                .route("/clear_canvas", post(clear_canvas_handler))
+                .route("/chat", post(chat_handler))
+                .route("/room_settings", post(room_settings_handler))
+                .route("/start_game", post(start_game_handler))
+                .route("/play", post(play_handler))
+                .route("/admin/config", get(admin_config))
+                .route("/admin/rooms", get(admin_rooms))
+                .route("/admin/rooms/:room_id", delete(admin_close_room))
+                .route(
+                    "/admin/rooms/:room_id/players/:username",
+                    delete(admin_kick),
+                )
+                .route("/admin/announce", post(admin_announce)),
         )
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(|| async { StatusCode::OK }))
+        .route("/readyz", get(readyz))
+        .route_layer(middleware::from_fn_with_state(state.clone(), track_latency))
         .route("/favicon.ico", get(|| async move { StatusCode::NOT_FOUND }))
         .nest_service(
             "/",
-            ServeDir::new("frontend/dist")
-                .not_found_service(ServeFile::new("frontend/dist/index.html")),
+            ServeDir::new(&static_dir)
+                .not_found_service(ServeFile::new(static_dir.join("index.html"))),
+        )
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &Request<_>| {
+                // Handlers fill in the room and player once they know them
+                info_span!(
+                    "request",
+                    method = %req.method(),
+                    path = %req.uri().path(),
+                    room_id = Empty,
+                    player = Empty,
+                )
+            }),
         )
+        .layer(middleware::from_fn_with_state(state.clone(), check_origin))
+        // Outside the trace layer, so handlers see the request span as the current one
         .layer(session_layer)
-        .with_state(state);
-    Ok(app)
+        .with_state(state.clone());
+    Ok((app, state))
 }
 
 async fn create_lobby(
+    _: Throttle<RoomCreation>,
     session: Session,
     State(state): State<Arc<AppState>>,
-    Json(JoinLobbyPost { username }): Json<JoinLobbyPost>,
-) -> String {
-    let code: u32 = rand::random();
-    {
-        let mut rooms = state.rooms.write().await;
-        rooms.insert(code, Arc::new(RoomState::new(format!("{code}"))));
+    Json(CreateLobbyPost {
+        username,
+        settings,
+        password,
+    }): Json<CreateLobbyPost>,
+) -> Result<String, StatusCode> {
+    let settings = settings.unwrap_or_else(|| state.config.room_defaults.clone());
+    if settings.validate().is_err() {
+        return Err(StatusCode::BAD_REQUEST);
     }
+    let password = password.filter(|p| !p.is_empty());
+    let code = state
+        .create_room(settings, username.clone(), password)
+        .await;
     session
         .insert(
             "user",
             SessionPlayer {
                 username,
-                room: code,
+                room: code.clone(),
+                spectator: false,
             },
         )
         .await
         .unwrap();
-    format!("{code}")
+    Ok(code)
 }
 
 async fn join_lobby(
     session: Session,
-    Path(room_id): Path<u32>,
-    Json(JoinLobbyPost { username }): Json<JoinLobbyPost>,
+    State(state): State<Arc<AppState>>,
+    ClientIp(ip): ClientIp,
+    Path(room_id): Path<String>,
+    Json(JoinLobbyPost {
+        username,
+        password,
+        spectate,
+    }): Json<JoinLobbyPost>,
 ) -> StatusCode {
+    if state.join_locked_out(ip).await {
+        return StatusCode::TOO_MANY_REQUESTS;
+    }
+    let room_id = normalize_room_code(&room_id);
+    let room = state.rooms.read().await.get(&room_id).cloned();
+    // Not a failed attempt, clients retrying a room that just closed shouldn't get locked out
+    let Some(room) = room else {
+        return StatusCode::NOT_FOUND;
+    };
+    if !room.check_password(password).await {
+        state.record_failed_join(ip).await;
+        return StatusCode::UNAUTHORIZED;
+    }
+    {
+        let gs = room.game_state.read().await;
+        if gs.is_banned(&username) {
+            return StatusCode::FORBIDDEN;
+        }
+        // Host and drawer rights go by name, so only the session that has it may use it
+        let rejoining = session
+            .get::<SessionPlayer>("user")
+            .await
+            .unwrap()
+            .is_some_and(|p| p.room == room_id && p.username == username);
+        if gs.has_member(&username) && !rejoining {
+            return StatusCode::CONFLICT;
+        }
+    }
     session
         .insert(
             "user",
             SessionPlayer {
                 username,
                 room: room_id,
+                spectator: spectate,
             },
         )
         .await
@@ -132,60 +797,350 @@ async fn join_lobby(
     StatusCode::OK
 }
 
-async fn leave_lobby(session: Session, State(state): State<Arc<AppState>>) -> Redirect {
-    let player = session.get::<SessionPlayer>("user").await.unwrap();
-    session.delete().await.unwrap();
-    if let Some(player) = player {
-        {
-            let mut rooms = state.rooms.write().await;
-            let room = match rooms.get(&player.room) {
-                Some(r) => r.clone(),
-                None => return Redirect::to("/"),
-            };
-            let mut gs = room.game_state.write().await;
-            let advance = gs.remove_player(Player {
-                username: player.username,
-                active: false,
+async fn list_rooms(State(state): State<Arc<AppState>>) -> Json<Vec<RoomListing>> {
+    let rooms = state.rooms.read().await;
+    let mut listings = vec![];
+    for room in rooms.values() {
+        let gs = room.game_state.read().await;
+        if gs.settings.public {
+            listings.push(RoomListing {
+                room_id: room.room_id.clone(),
+                players: gs.players.len(),
+                phase: gs.phase,
+                settings: gs.settings.clone(),
+                locked: room.password_hash.is_some(),
             });
-            if gs.players.is_empty() {
-                rooms.remove(&player.room);
-                return Redirect::to("/");
-            }
-            if advance {
-                gs.new_round();
+        }
+    }
+    listings.sort_by_key(|l| std::cmp::Reverse(l.players));
+    Json(listings)
+}
+
+/// Joins the fullest public room that still has space, or creates a new one
+async fn quick_play(
+    _: Throttle<RoomCreation>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Json(JoinLobbyPost { username, .. }): Json<JoinLobbyPost>,
+) -> String {
+    let mut best: Option<(String, usize)> = None;
+    {
+        let rooms = state.rooms.read().await;
+        for (code, room) in rooms.iter() {
+            let gs = room.game_state.read().await;
+            if !gs.settings.public
+                || room.password_hash.is_some()
+                || gs.phase == GamePhase::Finished
+                || gs.is_full()
+                || gs.is_banned(&username)
+                || gs.has_member(&username)
+            {
+                continue;
             }
-            if room.game_channel.send(true).is_err() {
-                println!("No receivers");
+            if best.as_ref().is_none_or(|(_, n)| gs.players.len() > *n) {
+                best = Some((code.clone(), gs.players.len()));
             }
         }
     }
-    Redirect::to("/")
+    let code = match best {
+        Some((code, _)) => code,
+        None => {
+            let settings = RoomSettings {
+                public: true,
+                ..state.config.room_defaults.clone()
+            };
+            state.create_room(settings, username.clone(), None).await
+        }
+    };
+    session
+        .insert(
+            "user",
+            SessionPlayer {
+                username,
+                room: code.clone(),
+                spectator: false,
+            },
+        )
+        .await
+        .unwrap();
+    code
+}
+
+async fn history(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<FinishedGame>>, StatusCode> {
+    match state.storage.finished_games(50).await {
+        Ok(games) => Ok(Json(games)),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
 }
 
-async fn get_player_name(session: Session) -> Json<Option<String>> {
-    Json(
-        session
-            .get::<SessionPlayer>("user")
-            .await
-            .unwrap()
-            .map(|p| p.username),
+/// Prometheus metrics
+async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let (mut players, mut spectators) = (0, 0);
+    let rooms: Vec<_> = state.rooms.read().await.values().cloned().collect();
+    for room in &rooms {
+        let gs = room.game_state.read().await;
+        players += gs.players.len() as i64;
+        spectators += gs.spectators.len() as i64;
+    }
+    state.metrics.rooms.set(rooms.len() as i64);
+    state.metrics.players.set(players);
+    state.metrics.spectators.set(spectators);
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
     )
 }
 
+async fn track_latency(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let method = req.method().clone();
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("other", MatchedPath::as_str)
+        .to_owned();
+    let start = Instant::now();
+    let res = next.run(req).await;
+    state
+        .metrics
+        .handler_latency
+        .with_label_values(&[method.as_str(), &path])
+        .observe(start.elapsed().as_secs_f64());
+    res
+}
+
+/// Refuses requests that change state or open a socket when the browser says they come from
+/// another site. Our own site is the `Host` the request was sent to, or one of the allowed origins
+async fn check_origin(
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        && !req.uri().path().starts_with("/ws/");
+    if !safe {
+        // Non-browser clients send no Origin, browsers always do for these requests
+        if let Some(origin) = req.headers().get(ORIGIN) {
+            let origin_host = origin
+                .to_str()
+                .ok()
+                .and_then(|o| o.split_once("://"))
+                .map(|(_, host)| host);
+            let host = req.headers().get(HOST).and_then(|h| h.to_str().ok());
+            let allowed = origin
+                .to_str()
+                .is_ok_and(|o| state.config.security.allowed_origins.iter().any(|a| a == o));
+            if !allowed && (origin_host.is_none() || origin_host != host) {
+                warn!(origin = ?origin, "Refused cross-site request");
+                return StatusCode::FORBIDDEN.into_response();
+            }
+        }
+    }
+    next.run(req).await
+}
+
+/// Ready to take players, unless shutting down or the storage is gone
+async fn readyz(State(state): State<Arc<AppState>>) -> StatusCode {
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    match state.storage.ping().await {
+        Ok(()) => StatusCode::OK,
+        Err(e) => {
+            error!("Storage is not reachable: {e}");
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+/// The running configuration, without secrets
+async fn admin_config(_: Admin, State(state): State<Arc<AppState>>) -> Json<Config> {
+    Json(state.config.clone())
+}
+
+#[derive(Serialize)]
+struct AdminRoom {
+    room_id: String,
+    locked: bool,
+    connections: usize,
+    game_state: GameState,
+}
+
+async fn admin_rooms(_: Admin, State(state): State<Arc<AppState>>) -> Json<Vec<AdminRoom>> {
+    let rooms: Vec<_> = state.rooms.read().await.values().cloned().collect();
+    let mut list = vec![];
+    for room in rooms {
+        list.push(AdminRoom {
+            room_id: room.room_id.clone(),
+            locked: room.password_hash.is_some(),
+            connections: room.connections.load(Ordering::SeqCst),
+            game_state: room.game_state.read().await.clone(),
+        });
+    }
+    list.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+    Json(list)
+}
+
+async fn admin_close_room(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path(room_id): Path<String>,
+) -> StatusCode {
+    let room_id = normalize_room_code(&room_id);
+    let Some(room) = state.rooms.write().await.remove(&room_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    state.forget_room(&room).await;
+    room.system_message("This room was closed by an admin.".into());
+    room.close_sockets(None, close_code::NORMAL, "Room closed");
+    info!(room_id, event = "room_closed", "Room closed by admin");
+    StatusCode::OK
+}
+
+async fn admin_kick(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Path((room_id, username)): Path<(String, String)>,
+) -> StatusCode {
+    let room_id = normalize_room_code(&room_id);
+    let Some(room) = state.rooms.read().await.get(&room_id).cloned() else {
+        return StatusCode::NOT_FOUND;
+    };
+    {
+        let gs = room.game_state.read().await;
+        if !gs.players.contains(&Player::new(username.clone())) && !gs.is_spectator(&username) {
+            return StatusCode::NOT_FOUND;
+        }
+    }
+    state.remove_player(&room_id, &username, true).await;
+    room.system_message(format!("{username} was kicked."));
+    room.close_sockets(Some(username.clone()), close_code::POLICY, "Kicked");
+    info!(
+        room_id,
+        player = username,
+        event = "player_kicked",
+        "Player kicked by admin"
+    );
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct AnnouncePost {
+    text: String,
+}
+
+/// Posts a message in the chat of every room
+async fn admin_announce(
+    _: Admin,
+    State(state): State<Arc<AppState>>,
+    Json(AnnouncePost { text }): Json<AnnouncePost>,
+) -> StatusCode {
+    if text.trim().is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    let rooms: Vec<_> = state.rooms.read().await.values().cloned().collect();
+    for room in rooms {
+        room.system_message(text.clone());
+    }
+    StatusCode::OK
+}
+
+async fn leave_lobby(session: Session, State(state): State<Arc<AppState>>) -> StatusCode {
+    let player = session.get::<SessionPlayer>("user").await.unwrap();
+    session.delete().await.unwrap();
+    if let Some(player) = player {
+        state
+            .remove_player(&player.room, &player.username, false)
+            .await;
+    }
+    StatusCode::OK
+}
+
+/// The room and name of this session's player, the client checks the room against the page
+async fn get_player(session: Session) -> Json<Option<SessionPlayer>> {
+    Json(session.get::<SessionPlayer>("user").await.unwrap())
+}
+
 async fn verify_session(session: &Session) -> Result<SessionPlayer> {
-    session
+    let player = session
         .get::<SessionPlayer>("user")
         .await
         .unwrap()
-        .ok_or(anyhow!("no player in this session"))
+        .ok_or(anyhow!("no player in this session"))?;
+    Span::current()
+        .record("room_id", &player.room)
+        .record("player", &player.username);
+    Ok(player)
 }
 
 async fn set_pixel_handler(
+    _: Throttle<Pixels>,
     session: Session,
     State(state): State<Arc<AppState>>,
-    Json(SetPixelPost { pixel_id, color }): Json<SetPixelPost>,
+    Json(SetPixelPost { x, y, color, layer }): Json<SetPixelPost>,
 ) -> StatusCode {
-    let player = match verify_session(&session).await {
+    paint(&session, &state, color, |canvas| {
+        canvas.set_pixel(layer, x, y, color).then_some(1)
+    })
+    .await
+}
+async fn stroke_handler(
+    _: Throttle<Pixels>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Json(StrokePost {
+        pixels,
+        color,
+        brush,
+        symmetry,
+        layer,
+        erase,
+    }): Json<StrokePost>,
+) -> StatusCode {
+    if pixels.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    paint(&session, &state, color, |canvas| {
+        // A stroke can't pass more pixels than the canvas has, and stamping is done holding
+        // the room
+        if pixels.len() > canvas.width * canvas.height {
+            return None;
+        }
+        let pixels = canvas.stamp(&pixels, brush, symmetry)?;
+        let done = if erase {
+            canvas.erase(layer, &pixels)
+        } else {
+            canvas.paint(layer, &pixels, color)
+        };
+        done.then_some(pixels.len())
+    })
+    .await
+}
+async fn fill_handler(
+    _: Throttle<Pixels>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Json(FillPost { x, y, color, layer }): Json<FillPost>,
+) -> StatusCode {
+    paint(&session, &state, color, |canvas| {
+        canvas.fill(layer, x, y, color)
+    })
+    .await
+}
+/// Paints with `color` for the session's player and tells the room once.
+/// `edit` returns how many pixels it painted, or None if they were off the canvas
+async fn paint(
+    session: &Session,
+    state: &AppState,
+    color: Color,
+    edit: impl FnOnce(&mut DrawCanvas) -> Option<usize>,
+) -> StatusCode {
+    let player = match verify_session(session).await {
         Ok(p) => p,
         Err(_) => return StatusCode::UNAUTHORIZED,
     };
@@ -196,15 +1151,27 @@ async fn set_pixel_handler(
     };
     {
         let mut gs = room.game_state.write().await;
-        gs.canvas.set_pixel(pixel_id, color);
-    }
-    if room.canvas_channel.send(true).is_err() {
-        println!("No receivers");
+        if !gs.may_draw(&player.username) {
+            return StatusCode::FORBIDDEN;
+        }
+        if gs.settings.palette.get(color).is_none() {
+            return StatusCode::BAD_REQUEST;
+        }
+        let Some(painted) = edit(&mut gs.canvas) else {
+            return StatusCode::BAD_REQUEST;
+        };
+        gs.record_drawing(&player.username);
+        state.metrics.pixels_set.inc_by(painted as u64);
+        room.send_canvas_changes(&mut gs.canvas);
     }
     StatusCode::OK
 }
-async fn clear_canvas_handler(session: Session, State(state): State<Arc<AppState>>) -> StatusCode {
-    let player = match verify_session(&session).await {
+async fn clear_canvas_handler(
+    _: Throttle<Clears>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> StatusCode {
+    let player = match verify_session(&session).await {
         Ok(p) => p,
         Err(_) => return StatusCode::UNAUTHORIZED,
     };
@@ -215,14 +1182,76 @@ async fn clear_canvas_handler(session: Session, State(state): State<Arc<AppState
     };
     {
         let mut gs = room.game_state.write().await;
+        if !gs.may_draw(&player.username) {
+            return StatusCode::FORBIDDEN;
+        }
         gs.canvas.clear();
+        state.metrics.canvas_clears.inc();
+        gs.record_drawing(&player.username);
+        room.send_canvas_changes(&mut gs.canvas);
     }
-    if room.canvas_channel.send(true).is_err() {
-        println!("No receivers");
+    StatusCode::OK
+}
+async fn undo_handler(
+    _: Throttle<Pixels>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> StatusCode {
+    step_history(&session, &state, DrawCanvas::undo).await
+}
+async fn redo_handler(
+    _: Throttle<Pixels>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+) -> StatusCode {
+    step_history(&session, &state, DrawCanvas::redo).await
+}
+async fn layers_handler(
+    _: Throttle<Pixels>,
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Json(change): Json<LayerPost>,
+) -> StatusCode {
+    step_history(&session, &state, |canvas| match change {
+        LayerPost::Add => canvas.add_layer(),
+        LayerPost::Remove { layer } => canvas.remove_layer(layer),
+        LayerPost::Move { from, to } => canvas.move_layer(from, to),
+        LayerPost::SetVisible { layer, visible } => canvas.set_layer_visible(layer, visible),
+    })
+    .await
+}
+/// Undoes or redoes an edit, or changes the layers, which only the drawer may do once the
+/// game is on
+async fn step_history(
+    session: &Session,
+    state: &AppState,
+    step: impl FnOnce(&mut DrawCanvas) -> bool,
+) -> StatusCode {
+    let player = match verify_session(session).await {
+        Ok(p) => p,
+        Err(_) => return StatusCode::UNAUTHORIZED,
+    };
+    let rooms = state.rooms.read().await;
+    let room = match rooms.get(&player.room) {
+        Some(r) => r.clone(),
+        None => return StatusCode::NOT_FOUND,
+    };
+    {
+        let mut gs = room.game_state.write().await;
+        if !gs.may_draw(&player.username) {
+            return StatusCode::FORBIDDEN;
+        }
+        // Not counted as drawing, flipping layers or history back and forth would keep an
+        // idle drawer's turn going without anything new for the guessers
+        if !step(&mut gs.canvas) {
+            return StatusCode::CONFLICT;
+        }
+        room.send_canvas_changes(&mut gs.canvas);
     }
     StatusCode::OK
 }
 async fn chat_handler(
+    _: Throttle<Chat>,
     session: Session,
     State(state): State<Arc<AppState>>,
     Json(chat_message): Json<ChatMessage>,
@@ -240,7 +1269,21 @@ async fn chat_handler(
     let text = chat_message.text;
     let correct = {
         let gs = room.game_state.read().await;
-        text.trim().to_lowercase() == gs.prompt
+        if gs.is_banned(&username) {
+            return StatusCode::FORBIDDEN;
+        }
+        let guessing = gs.phase == GamePhase::Playing
+            && !gs.is_drawing(&username)
+            && !gs.is_spectator(&username);
+        let correct = guessing && text.trim().to_lowercase() == gs.prompt;
+        if guessing {
+            state
+                .metrics
+                .guesses
+                .with_label_values(&[if correct { "true" } else { "false" }])
+                .inc();
+        }
+        correct
     };
     if room
         .chat_channel
@@ -250,16 +1293,17 @@ async fn chat_handler(
         })
         .is_err()
     {
-        println!("No receivers");
+        debug!("No receivers");
     }
     if correct {
         let mut gs = room.game_state.write().await;
         gs.new_round();
+        state.metrics.rounds.inc();
         if room.game_channel.send(true).is_err() {
-            println!("No receivers");
+            debug!("No receivers");
         }
-        if room.canvas_channel.send(true).is_err() {
-            println!("No receivers");
+        if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+            debug!("No receivers");
         }
         if room
             .chat_channel
@@ -269,145 +1313,1970 @@ async fn chat_handler(
             })
             .is_err()
         {
-            println!("No receivers");
+            debug!("No receivers");
+        }
+    }
+    StatusCode::OK
+}
+async fn room_settings_handler(
+    session: Session,
+    State(state): State<Arc<AppState>>,
+    Json(settings): Json<RoomSettings>,
+) -> StatusCode {
+    let player = match verify_session(&session).await {
+        Ok(p) => p,
+        Err(_) => return StatusCode::UNAUTHORIZED,
+    };
+    let rooms = state.rooms.read().await;
+    let room = match rooms.get(&player.room) {
+        Some(r) => r.clone(),
+        None => return StatusCode::NOT_FOUND,
+    };
+    {
+        let mut gs = room.game_state.write().await;
+        if gs.host != player.username {
+            return StatusCode::FORBIDDEN;
+        }
+        if gs.update_settings(settings).is_err() {
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+    if room.game_channel.send(true).is_err() {
+        debug!("No receivers");
+    }
+    if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+        debug!("No receivers");
+    }
+    StatusCode::OK
+}
+async fn start_game_handler(session: Session, State(state): State<Arc<AppState>>) -> StatusCode {
+    let player = match verify_session(&session).await {
+        Ok(p) => p,
+        Err(_) => return StatusCode::UNAUTHORIZED,
+    };
+    let rooms = state.rooms.read().await;
+    let room = match rooms.get(&player.room) {
+        Some(r) => r.clone(),
+        None => return StatusCode::NOT_FOUND,
+    };
+    {
+        let mut gs = room.game_state.write().await;
+        if gs.host != player.username {
+            return StatusCode::FORBIDDEN;
         }
+        if gs.phase == GamePhase::Playing {
+            return StatusCode::CONFLICT;
+        }
+        gs.start_game();
+    }
+    info!(event = "game_started", "Game started");
+    if room.game_channel.send(true).is_err() {
+        debug!("No receivers");
+    }
+    if room.canvas_channel.send(CanvasEvent::Resync).is_err() {
+        debug!("No receivers");
     }
     StatusCode::OK
 }
+/// Lets a spectator take a free player slot, or queue for the next one
+async fn play_handler(session: Session, State(state): State<Arc<AppState>>) -> StatusCode {
+    let player = match verify_session(&session).await {
+        Ok(p) => p,
+        Err(_) => return StatusCode::UNAUTHORIZED,
+    };
+    let rooms = state.rooms.read().await;
+    let room = match rooms.get(&player.room) {
+        Some(r) => r.clone(),
+        None => return StatusCode::NOT_FOUND,
+    };
+    let promoted = {
+        let mut gs = room.game_state.write().await;
+        if !gs.is_spectator(&player.username) {
+            return StatusCode::CONFLICT;
+        }
+        gs.request_play(&player.username)
+    };
+    session
+        .insert(
+            "user",
+            SessionPlayer {
+                spectator: false,
+                ..player
+            },
+        )
+        .await
+        .unwrap();
+    if room.game_channel.send(true).is_err() {
+        debug!("No receivers");
+    }
+    if promoted {
+        StatusCode::OK
+    } else {
+        StatusCode::ACCEPTED
+    }
+}
 
 mod ws {
-    use std::sync::Arc;
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Instant,
+    };
 
     use axum::{
         extract::{
-            ws::{Message, WebSocket, WebSocketUpgrade},
+            ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
             Path,
         },
-        response::Response,
+        http::StatusCode,
+        response::{IntoResponse, Response},
         Extension,
     };
-    use common::{ChatMessage, GameInfo, Player, SessionPlayer};
+    use common::{
+        normalize_room_code, CanvasDelta, CanvasUpdate, CanvasView, ChatMessage, GameInfo,
+        GamePhase, Player, SessionPlayer,
+    };
+    use prometheus::{Histogram, IntCounter, IntGauge};
+    use serde::Serialize;
+    use tokio::sync::broadcast::{error::RecvError, Receiver};
     use tower_sessions::Session;
+    use tracing::{debug, field::Empty, info, instrument, Span};
+
+    use crate::{AppState, ClientIp, RoomState};
+
+    /// Counts an open socket towards the room being in use
+    struct Connection(Arc<RoomState>, IntGauge);
+    impl Connection {
+        fn open(room: Arc<RoomState>, sockets: IntGauge) -> Self {
+            room.connections.fetch_add(1, Ordering::SeqCst);
+            sockets.inc();
+            Self(room, sockets)
+        }
+    }
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            self.1.dec();
+            if self.0.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+                *self.0.idle_since.lock().unwrap() = Instant::now();
+            }
+        }
+    }
 
-    use crate::AppState;
+    /// Asks the sockets of a room to close, only the ones of `username` if set
+    #[derive(Clone)]
+    pub struct CloseSockets {
+        pub username: Option<String>,
+        pub frame: CloseFrame<'static>,
+    }
+
+    /// Tells the canvas sockets of a room what changed
+    #[derive(Clone)]
+    pub enum CanvasEvent {
+        /// Just these pixels
+        Pixels(Arc<CanvasDelta>),
+        /// More than pixels, like the layers or the size, so the whole canvas is sent again
+        Resync,
+    }
 
+    /// Waits for the next broadcast, or returns None if the client goes away first
+    /// or the socket is closed by the server. `resync` stands in for missed broadcasts, if
+    /// the next one doesn't cover them
+    async fn next_event<T: Clone>(
+        socket: &mut WebSocket,
+        rx: &mut Receiver<T>,
+        close: &mut Receiver<CloseSockets>,
+        username: Option<&str>,
+        lagged: &IntCounter,
+        resync: Option<&T>,
+    ) -> Option<T> {
+        loop {
+            tokio::select! {
+                // Deliver what is already queued, like the restart notice, before closing
+                biased;
+                msg = rx.recv() => match msg {
+                    Ok(msg) => return Some(msg),
+                    // Game events just say that something changed, so the next one covers
+                    // the missed ones. Missed chat messages are gone
+                    Err(RecvError::Lagged(n)) => {
+                        lagged.inc_by(n);
+                        if let Some(resync) = resync {
+                            return Some(resync.clone());
+                        }
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                msg = close.recv() => match msg {
+                    Ok(CloseSockets { username: Some(target), .. })
+                        if username != Some(target.as_str()) => {}
+                    Ok(CloseSockets { frame, .. }) => {
+                        let _ = socket.send(Message::Close(Some(frame))).await;
+                        return None;
+                    }
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                },
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => return None,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+
+    #[derive(Debug)]
     pub enum WsStreamType {
         Game,
         Canvas,
         Chat,
     }
+    impl WsStreamType {
+        fn name(&self) -> &'static str {
+            match self {
+                Self::Game => "game",
+                Self::Canvas => "canvas",
+                Self::Chat => "chat",
+            }
+        }
+    }
+
+    /// Returns false if the client is gone
+    async fn send_json<T: Serialize>(
+        socket: &mut WebSocket,
+        value: &T,
+        frame_size: &Histogram,
+    ) -> bool {
+        send_text(socket, serde_json::to_string(value).unwrap(), frame_size).await
+    }
+    async fn send_text(socket: &mut WebSocket, text: String, frame_size: &Histogram) -> bool {
+        frame_size.observe(text.len() as f64);
+        socket.send(Message::from(text)).await.is_ok()
+    }
 
     pub async fn ws_handler(
         ws: WebSocketUpgrade,
         session: Session,
-        Path(room_id): Path<u32>,
+        ClientIp(ip): ClientIp,
+        Path(room_id): Path<String>,
         Extension(app_state): Extension<Arc<AppState>>,
         st: WsStreamType,
     ) -> Response {
+        // The sockets are being closed, new ones would only keep the server waiting
+        if app_state.shutting_down.load(Ordering::SeqCst) {
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+        if app_state.join_locked_out(ip).await {
+            return StatusCode::TOO_MANY_REQUESTS.into_response();
+        }
+        let room_id = normalize_room_code(&room_id);
         let player = session.get::<SessionPlayer>("user").await.unwrap();
+        let room = app_state.rooms.read().await.get(&room_id).cloned();
+        let Some(room) = room else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        // Only players that gave the password to join_lobby get to see protected rooms
+        if room.password_hash.is_some() && player.as_ref().is_none_or(|p| p.room != room_id) {
+            app_state.record_failed_join(ip).await;
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        if let Some(player) = player.as_ref().filter(|p| p.room == room_id) {
+            if room.game_state.read().await.is_banned(&player.username) {
+                return StatusCode::FORBIDDEN.into_response();
+            }
+        }
         ws.on_upgrade(move |socket| handle_socket(socket, player, room_id, app_state, st))
     }
 
+    #[instrument(name = "socket", skip_all, fields(room_id = %room_id, stream = ?st, player = Empty))]
     async fn handle_socket(
         mut socket: WebSocket,
         player: Option<SessionPlayer>,
-        room_id: u32,
+        room_id: String,
         state: Arc<AppState>,
         st: WsStreamType,
     ) {
-        let rooms = state.rooms.read().await;
-        let room = match rooms.get(&room_id) {
+        let room = match state.rooms.read().await.get(&room_id) {
             Some(r) => r.clone(),
             None => return,
         };
+        let stream = st.name();
+        let _connection = Connection::open(
+            room.clone(),
+            state.metrics.sockets.with_label_values(&[stream]),
+        );
+        let lagged = state.metrics.broadcast_lag.with_label_values(&[stream]);
+        let frame_size = state.metrics.frame_size.with_label_values(&[stream]);
+        let mut close = room.close_channel.subscribe();
+        // Subscribe before joining, so the player sees their own greeting
+        let mut chat_rx = room.chat_channel.subscribe();
+        // A session from another room just watches, like visitors without one
+        let player = player.filter(|p| p.room == room_id);
+        if let Some(player) = &player {
+            Span::current().record("player", &player.username);
+        }
+        debug!("Socket opened");
         let (new, player) = if let Some(player) = player {
             let mut gs = room.game_state.write().await;
-            let player = Player {
-                username: player.username,
-                active: false,
-            };
-            (gs.add_player(player.clone()), Some(player))
+            let spectate = player.spectator;
+            let player = Player::new(player.username);
+            (gs.add_player(player.clone(), spectate), Some(player))
         } else {
             (false, None)
         };
-        if new && room.game_channel.send(true).is_err() {
-            println!("No receivers");
+        // Only greet players once, not every time they reconnect
+        if new {
+            if room.game_channel.send(true).is_err() {
+                debug!("No receivers");
+            }
+            if let Some(player) = &player {
+                info!(event = "player_joined", "Player joined");
+                if room
+                    .chat_channel
+                    .send(ChatMessage {
+                        username: "SYSTEM".into(),
+                        text: format!("{} joined!", player.username),
+                    })
+                    .is_err()
+                {
+                    debug!("No receivers");
+                }
+            }
         }
+        let username = player.as_ref().map(|p| p.username.as_str());
         match st {
             WsStreamType::Canvas => {
                 let mut rx = room.canvas_channel.subscribe();
+                let mut event = CanvasEvent::Resync;
+                // Whether the last whole canvas sent had the layers
+                let mut with_layers = false;
                 loop {
-                    let gs = { (*room.game_state.read().await).clone() };
-                    if socket
-                        .send(Message::from(serde_json::to_string(&gs.canvas).unwrap()))
-                        .await
-                        .is_err()
-                    {
+                    // Serialized while holding the room, instead of copying the canvas out
+                    let update = {
+                        let gs = room.game_state.read().await;
+                        let canvas = gs.canvas_for(username);
+                        match event {
+                            // Players who just got to draw need the layers, and ones who
+                            // can't anymore shouldn't keep them
+                            CanvasEvent::Pixels(delta)
+                                if canvas.layers.is_empty() != with_layers =>
+                            {
+                                serde_json::to_string(&CanvasUpdate::<CanvasView>::Pixels(
+                                    CanvasDelta {
+                                        grid: delta.grid.clone(),
+                                        layers: if with_layers {
+                                            delta.layers.clone()
+                                        } else {
+                                            vec![]
+                                        },
+                                    },
+                                ))
+                            }
+                            _ => {
+                                with_layers = !canvas.layers.is_empty();
+                                serde_json::to_string(&CanvasUpdate::Full(canvas))
+                            }
+                        }
+                        .unwrap()
+                    };
+                    if !send_text(&mut socket, update, &frame_size).await {
                         // client disconnected
                         return;
                     }
-                    rx.recv().await.expect("Channel recv error");
+                    let resync = Some(&CanvasEvent::Resync);
+                    match next_event(&mut socket, &mut rx, &mut close, username, &lagged, resync)
+                        .await
+                    {
+                        Some(next) => event = next,
+                        None => return,
+                    }
                 }
             }
             WsStreamType::Game => {
                 let mut rx = room.game_channel.subscribe();
                 loop {
-                    let gs = { (*room.game_state.read().await).clone() };
-                    let prompt = if !player
-                        .clone()
-                        .map(|ps| gs.players.iter().find(|p| **p == ps).unwrap().active)
-                        .unwrap_or(false)
-                    {
-                        gs.prompt.replace(|c: char| c.is_alphabetic(), "_")
-                    } else {
-                        gs.prompt
+                    // Only what GameInfo needs is copied, not the canvas and its history
+                    let info = {
+                        let gs = room.game_state.read().await;
+                        let drawing = username.is_some_and(|u| gs.is_drawing(u));
+                        GameInfo {
+                            room_id: room.room_id.clone(),
+                            prompt: if gs.phase == GamePhase::Playing && !drawing {
+                                gs.masked_prompt()
+                            } else {
+                                gs.prompt.clone()
+                            },
+                            players: gs.players.clone(),
+                            spectators: gs.spectators.clone(),
+                            host: gs.host.clone(),
+                            phase: gs.phase,
+                            round: gs.round,
+                            time_left: gs.time_left(),
+                            settings: gs.settings.clone(),
+                        }
                     };
-                    let players = gs.players;
-                    if socket
-                        .send(Message::from(
-                            serde_json::to_string(&GameInfo {
-                                room_id: room.room_id.clone(),
-                                prompt,
-                                players,
-                            })
-                            .unwrap(),
-                        ))
+                    if !send_json(&mut socket, &info, &frame_size).await {
+                        // client disconnected
+                        return;
+                    }
+                    if next_event(&mut socket, &mut rx, &mut close, username, &lagged, None)
                         .await
-                        .is_err()
+                        .is_none()
                     {
-                        // client disconnected
                         return;
                     }
-                    rx.recv().await.expect("Channel recv error");
                 }
             }
-            WsStreamType::Chat => {
-                let mut rx = room.chat_channel.subscribe();
-                if player.is_some()
-                    && room
-                        .chat_channel
-                        .send(ChatMessage {
-                            username: "SYSTEM".into(),
-                            text: format!("{} joined!", player.clone().unwrap().username),
-                        })
-                        .is_err()
-                {
-                    println!("No receivers");
+            WsStreamType::Chat => loop {
+                let Some(msg) = next_event(
+                    &mut socket,
+                    &mut chat_rx,
+                    &mut close,
+                    username,
+                    &lagged,
+                    None,
+                )
+                .await
+                else {
+                    return;
+                };
+                if !send_json(&mut socket, &msg, &frame_size).await {
+                    // client disconnected
+                    return;
                 }
-                loop {
-                    let msg = rx.recv().await.expect("Channel recv error");
-                    {
-                        if socket
-                            .send(Message::from(serde_json::to_string(&msg).unwrap()))
-                            .await
-                            .is_err()
-                        {
-                            // client disconnected
-                            return;
-                        }
+            },
+        };
+    }
+}
+
+mod storage {
+    use std::{
+        collections::HashMap,
+        fmt,
+        path::Path,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use anyhow::Result;
+    use axum::async_trait;
+    use common::{FinishedGame, GameState};
+    use rusqlite::{params, Connection, OptionalExtension};
+    use serde::{Deserialize, Serialize};
+    use tower_sessions::{
+        session::{Id, Record},
+        session_store, SessionStore,
+    };
+    use tracing::warn;
+
+    /// Everything needed to bring a room back after a restart
+    #[derive(Clone, Serialize, Deserialize)]
+    pub struct RoomSnapshot {
+        pub room_id: String,
+        pub password_hash: Option<String>,
+        pub game_state: GameState,
+    }
+
+    pub fn unix_now() -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64
+    }
+
+    #[async_trait]
+    pub trait Storage: Send + Sync + 'static {
+        async fn save_room(&self, room: RoomSnapshot) -> Result<()>;
+        async fn delete_room(&self, room_id: &str) -> Result<()>;
+        async fn load_rooms(&self) -> Result<Vec<RoomSnapshot>>;
+        async fn save_finished_game(&self, game: FinishedGame) -> Result<()>;
+        /// Most recently finished first
+        async fn finished_games(&self, limit: usize) -> Result<Vec<FinishedGame>>;
+        async fn save_session(&self, record: &Record) -> Result<()>;
+        /// Expired sessions are treated as missing
+        async fn load_session(&self, id: &Id) -> Result<Option<Record>>;
+        async fn delete_session(&self, id: &Id) -> Result<()>;
+        async fn delete_expired_sessions(&self) -> Result<()>;
+        /// Checks that the storage can be reached
+        async fn ping(&self) -> Result<()>;
+    }
+
+    /// Keeps everything in memory, so nothing survives a restart
+    #[derive(Default)]
+    pub struct MemoryStorage {
+        rooms: Mutex<HashMap<String, RoomSnapshot>>,
+        finished_games: Mutex<Vec<FinishedGame>>,
+        sessions: Mutex<HashMap<Id, Record>>,
+    }
+
+    #[async_trait]
+    impl Storage for MemoryStorage {
+        async fn save_room(&self, room: RoomSnapshot) -> Result<()> {
+            self.rooms
+                .lock()
+                .unwrap()
+                .insert(room.room_id.clone(), room);
+            Ok(())
+        }
+        async fn delete_room(&self, room_id: &str) -> Result<()> {
+            self.rooms.lock().unwrap().remove(room_id);
+            Ok(())
+        }
+        async fn load_rooms(&self) -> Result<Vec<RoomSnapshot>> {
+            Ok(self.rooms.lock().unwrap().values().cloned().collect())
+        }
+        async fn save_finished_game(&self, game: FinishedGame) -> Result<()> {
+            self.finished_games.lock().unwrap().push(game);
+            Ok(())
+        }
+        async fn finished_games(&self, limit: usize) -> Result<Vec<FinishedGame>> {
+            let games = self.finished_games.lock().unwrap();
+            Ok(games.iter().rev().take(limit).cloned().collect())
+        }
+        async fn save_session(&self, record: &Record) -> Result<()> {
+            self.sessions
+                .lock()
+                .unwrap()
+                .insert(record.id, record.clone());
+            Ok(())
+        }
+        async fn load_session(&self, id: &Id) -> Result<Option<Record>> {
+            let sessions = self.sessions.lock().unwrap();
+            Ok(sessions
+                .get(id)
+                .filter(|r| r.expiry_date.unix_timestamp() > unix_now())
+                .cloned())
+        }
+        async fn delete_session(&self, id: &Id) -> Result<()> {
+            self.sessions.lock().unwrap().remove(id);
+            Ok(())
+        }
+        async fn delete_expired_sessions(&self) -> Result<()> {
+            let now = unix_now();
+            self.sessions
+                .lock()
+                .unwrap()
+                .retain(|_, r| r.expiry_date.unix_timestamp() > now);
+            Ok(())
+        }
+        async fn ping(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Embedded SQLite database, values are stored as JSON. Used by the standalone server
+    #[cfg_attr(feature = "shuttle", allow(dead_code))]
+    pub struct SqliteStorage {
+        conn: Arc<Mutex<Connection>>,
+    }
+
+    #[cfg_attr(feature = "shuttle", allow(dead_code))]
+    impl SqliteStorage {
+        pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+            let conn = Connection::open(path)?;
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS rooms (
+                    room_id TEXT PRIMARY KEY,
+                    snapshot TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS finished_games (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    game TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS sessions (
+                    id TEXT PRIMARY KEY,
+                    record TEXT NOT NULL,
+                    expires_at INTEGER NOT NULL
+                );",
+            )?;
+            Ok(Self {
+                conn: Arc::new(Mutex::new(conn)),
+            })
+        }
+        /// Runs a query on the blocking thread pool
+        async fn with_conn<T: Send + 'static>(
+            &self,
+            f: impl FnOnce(&Connection) -> Result<T> + Send + 'static,
+        ) -> Result<T> {
+            let conn = self.conn.clone();
+            tokio::task::spawn_blocking(move || f(&conn.lock().unwrap())).await?
+        }
+    }
+
+    #[async_trait]
+    impl Storage for SqliteStorage {
+        async fn save_room(&self, room: RoomSnapshot) -> Result<()> {
+            let snapshot = serde_json::to_string(&room)?;
+            self.with_conn(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO rooms (room_id, snapshot) VALUES (?1, ?2)",
+                    params![room.room_id, snapshot],
+                )?;
+                Ok(())
+            })
+            .await
+        }
+        async fn delete_room(&self, room_id: &str) -> Result<()> {
+            let room_id = room_id.to_owned();
+            self.with_conn(move |conn| {
+                conn.execute("DELETE FROM rooms WHERE room_id = ?1", params![room_id])?;
+                Ok(())
+            })
+            .await
+        }
+        async fn load_rooms(&self) -> Result<Vec<RoomSnapshot>> {
+            self.with_conn(|conn| {
+                let mut stmt = conn.prepare("SELECT snapshot FROM rooms")?;
+                let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+                let mut rooms = vec![];
+                for row in rows {
+                    // Rooms saved by an older version may not fit the current format
+                    match serde_json::from_str(&row?) {
+                        Ok(room) => rooms.push(room),
+                        Err(e) => warn!("Dropping a saved room that can't be read: {e}"),
+                    }
+                }
+                Ok(rooms)
+            })
+            .await
+        }
+        async fn save_finished_game(&self, game: FinishedGame) -> Result<()> {
+            let game = serde_json::to_string(&game)?;
+            self.with_conn(move |conn| {
+                conn.execute(
+                    "INSERT INTO finished_games (game) VALUES (?1)",
+                    params![game],
+                )?;
+                Ok(())
+            })
+            .await
+        }
+        async fn finished_games(&self, limit: usize) -> Result<Vec<FinishedGame>> {
+            self.with_conn(move |conn| {
+                let mut stmt =
+                    conn.prepare("SELECT game FROM finished_games ORDER BY id DESC LIMIT ?1")?;
+                let rows = stmt.query_map(params![limit as i64], |row| row.get::<_, String>(0))?;
+                let mut games = vec![];
+                for row in rows {
+                    games.push(serde_json::from_str(&row?)?);
+                }
+                Ok(games)
+            })
+            .await
+        }
+        async fn save_session(&self, record: &Record) -> Result<()> {
+            let id = record.id.to_string();
+            let expires_at = record.expiry_date.unix_timestamp();
+            let record = serde_json::to_string(record)?;
+            self.with_conn(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO sessions (id, record, expires_at) VALUES (?1, ?2, ?3)",
+                    params![id, record, expires_at],
+                )?;
+                Ok(())
+            })
+            .await
+        }
+        async fn load_session(&self, id: &Id) -> Result<Option<Record>> {
+            let id = id.to_string();
+            self.with_conn(move |conn| {
+                let record: Option<String> = conn
+                    .query_row(
+                        "SELECT record FROM sessions WHERE id = ?1 AND expires_at > ?2",
+                        params![id, unix_now()],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                Ok(record.map(|r| serde_json::from_str(&r)).transpose()?)
+            })
+            .await
+        }
+        async fn delete_session(&self, id: &Id) -> Result<()> {
+            let id = id.to_string();
+            self.with_conn(move |conn| {
+                conn.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+                Ok(())
+            })
+            .await
+        }
+        async fn delete_expired_sessions(&self) -> Result<()> {
+            self.with_conn(|conn| {
+                conn.execute(
+                    "DELETE FROM sessions WHERE expires_at <= ?1",
+                    params![unix_now()],
+                )?;
+                Ok(())
+            })
+            .await
+        }
+        async fn ping(&self) -> Result<()> {
+            self.with_conn(|conn| {
+                conn.query_row("SELECT 1", [], |_| Ok(()))?;
+                Ok(())
+            })
+            .await
+        }
+    }
+
+    /// Shuttle's shared Postgres database, values are stored as JSON like in SQLite.
+    /// Shuttle's filesystem is wiped on every deployment, so a SQLite file wouldn't last there
+    #[cfg(feature = "shuttle")]
+    pub struct PostgresStorage {
+        pool: sqlx::PgPool,
+    }
+
+    #[cfg(feature = "shuttle")]
+    impl PostgresStorage {
+        pub async fn new(pool: sqlx::PgPool) -> Result<Self> {
+            sqlx::raw_sql(
+                "CREATE TABLE IF NOT EXISTS rooms (
+                    room_id TEXT PRIMARY KEY,
+                    snapshot TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS finished_games (
+                    id BIGSERIAL PRIMARY KEY,
+                    game TEXT NOT NULL
+                );
+                CREATE TABLE IF NOT EXISTS sessions (
+                    id TEXT PRIMARY KEY,
+                    record TEXT NOT NULL,
+                    expires_at BIGINT NOT NULL
+                );",
+            )
+            .execute(&pool)
+            .await?;
+            Ok(Self { pool })
+        }
+    }
+
+    #[cfg(feature = "shuttle")]
+    #[async_trait]
+    impl Storage for PostgresStorage {
+        async fn save_room(&self, room: RoomSnapshot) -> Result<()> {
+            let snapshot = serde_json::to_string(&room)?;
+            sqlx::query(
+                "INSERT INTO rooms (room_id, snapshot) VALUES ($1, $2)
+                ON CONFLICT (room_id) DO UPDATE SET snapshot = EXCLUDED.snapshot",
+            )
+            .bind(room.room_id)
+            .bind(snapshot)
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        async fn delete_room(&self, room_id: &str) -> Result<()> {
+            sqlx::query("DELETE FROM rooms WHERE room_id = $1")
+                .bind(room_id)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        async fn load_rooms(&self) -> Result<Vec<RoomSnapshot>> {
+            let rows: Vec<(String,)> = sqlx::query_as("SELECT snapshot FROM rooms")
+                .fetch_all(&self.pool)
+                .await?;
+            let mut rooms = vec![];
+            for (snapshot,) in rows {
+                // Rooms saved by an older version may not fit the current format
+                match serde_json::from_str(&snapshot) {
+                    Ok(room) => rooms.push(room),
+                    Err(e) => warn!("Dropping a saved room that can't be read: {e}"),
+                }
+            }
+            Ok(rooms)
+        }
+        async fn save_finished_game(&self, game: FinishedGame) -> Result<()> {
+            sqlx::query("INSERT INTO finished_games (game) VALUES ($1)")
+                .bind(serde_json::to_string(&game)?)
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        async fn finished_games(&self, limit: usize) -> Result<Vec<FinishedGame>> {
+            let rows: Vec<(String,)> =
+                sqlx::query_as("SELECT game FROM finished_games ORDER BY id DESC LIMIT $1")
+                    .bind(limit as i64)
+                    .fetch_all(&self.pool)
+                    .await?;
+            let mut games = vec![];
+            for (game,) in rows {
+                games.push(serde_json::from_str(&game)?);
+            }
+            Ok(games)
+        }
+        async fn save_session(&self, record: &Record) -> Result<()> {
+            sqlx::query(
+                "INSERT INTO sessions (id, record, expires_at) VALUES ($1, $2, $3)
+                ON CONFLICT (id) DO UPDATE SET record = EXCLUDED.record, expires_at = EXCLUDED.expires_at",
+            )
+            .bind(record.id.to_string())
+            .bind(serde_json::to_string(record)?)
+            .bind(record.expiry_date.unix_timestamp())
+            .execute(&self.pool)
+            .await?;
+            Ok(())
+        }
+        async fn load_session(&self, id: &Id) -> Result<Option<Record>> {
+            let record: Option<(String,)> =
+                sqlx::query_as("SELECT record FROM sessions WHERE id = $1 AND expires_at > $2")
+                    .bind(id.to_string())
+                    .bind(unix_now())
+                    .fetch_optional(&self.pool)
+                    .await?;
+            Ok(record.map(|(r,)| serde_json::from_str(&r)).transpose()?)
+        }
+        async fn delete_session(&self, id: &Id) -> Result<()> {
+            sqlx::query("DELETE FROM sessions WHERE id = $1")
+                .bind(id.to_string())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        async fn delete_expired_sessions(&self) -> Result<()> {
+            sqlx::query("DELETE FROM sessions WHERE expires_at <= $1")
+                .bind(unix_now())
+                .execute(&self.pool)
+                .await?;
+            Ok(())
+        }
+        async fn ping(&self) -> Result<()> {
+            sqlx::query("SELECT 1").execute(&self.pool).await?;
+            Ok(())
+        }
+    }
+
+    /// Lets the session layer keep its sessions in our storage
+    #[derive(Clone)]
+    pub struct SessionStorage(pub Arc<dyn Storage>);
+
+    impl fmt::Debug for SessionStorage {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("SessionStorage")
+        }
+    }
+
+    fn backend_error(e: anyhow::Error) -> session_store::Error {
+        session_store::Error::Backend(e.to_string())
+    }
+
+    #[async_trait]
+    impl SessionStore for SessionStorage {
+        async fn create(&self, record: &mut Record) -> session_store::Result<()> {
+            while self.load(&record.id).await?.is_some() {
+                record.id = Id::default();
+            }
+            self.save(record).await
+        }
+        async fn save(&self, record: &Record) -> session_store::Result<()> {
+            self.0.save_session(record).await.map_err(backend_error)
+        }
+        async fn load(&self, id: &Id) -> session_store::Result<Option<Record>> {
+            self.0.load_session(id).await.map_err(backend_error)
+        }
+        async fn delete(&self, id: &Id) -> session_store::Result<()> {
+            self.0.delete_session(id).await.map_err(backend_error)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use common::RoomSettings;
+        use tower_sessions::cookie::time::{Duration, OffsetDateTime};
+
+        use super::*;
+
+        fn storages() -> Vec<Box<dyn Storage>> {
+            vec![
+                Box::new(MemoryStorage::default()),
+                Box::new(SqliteStorage::open(":memory:").unwrap()),
+            ]
+        }
+        fn room(room_id: &str, host: &str) -> RoomSnapshot {
+            RoomSnapshot {
+                room_id: room_id.into(),
+                password_hash: None,
+                game_state: GameState::new(RoomSettings::default(), host.into()),
+            }
+        }
+        fn session(expires_in: Duration) -> Record {
+            Record {
+                id: Id::default(),
+                data: HashMap::from([("user".into(), "alice".into())]),
+                expiry_date: OffsetDateTime::now_utc() + expires_in,
+            }
+        }
+
+        #[tokio::test]
+        async fn rooms_round_trip() {
+            for storage in storages() {
+                storage.save_room(room("ABCD", "alice")).await.unwrap();
+                storage.save_room(room("EFGH", "bob")).await.unwrap();
+                // Saving again replaces the snapshot
+                storage.save_room(room("ABCD", "carol")).await.unwrap();
+                let mut rooms = storage.load_rooms().await.unwrap();
+                rooms.sort_by(|a, b| a.room_id.cmp(&b.room_id));
+                let hosts: Vec<_> = rooms.iter().map(|r| r.game_state.host.as_str()).collect();
+                assert_eq!(hosts, ["carol", "bob"]);
+
+                storage.delete_room("ABCD").await.unwrap();
+                let rooms = storage.load_rooms().await.unwrap();
+                assert_eq!(rooms.len(), 1);
+                assert_eq!(rooms[0].room_id, "EFGH");
+            }
+        }
+
+        #[tokio::test]
+        async fn sessions_round_trip() {
+            for storage in storages() {
+                let record = session(Duration::hours(1));
+                storage.save_session(&record).await.unwrap();
+                assert_eq!(
+                    storage.load_session(&record.id).await.unwrap(),
+                    Some(record.clone())
+                );
+                storage.delete_session(&record.id).await.unwrap();
+                assert_eq!(storage.load_session(&record.id).await.unwrap(), None);
+            }
+        }
+
+        #[tokio::test]
+        async fn expired_sessions_are_gone() {
+            for storage in storages() {
+                let expired = session(Duration::seconds(-10));
+                let live = session(Duration::hours(1));
+                storage.save_session(&expired).await.unwrap();
+                storage.save_session(&live).await.unwrap();
+                assert_eq!(storage.load_session(&expired.id).await.unwrap(), None);
+
+                storage.delete_expired_sessions().await.unwrap();
+                assert_eq!(storage.load_session(&expired.id).await.unwrap(), None);
+                assert!(storage.load_session(&live.id).await.unwrap().is_some());
+            }
+        }
+
+        #[tokio::test]
+        async fn history_is_newest_first() {
+            for storage in storages() {
+                for (i, room_id) in ["A", "B", "C"].into_iter().enumerate() {
+                    let game = FinishedGame {
+                        room_id: room_id.into(),
+                        players: vec!["alice".into()],
+                        rounds: 1,
+                        finished_at: i as u64,
+                    };
+                    storage.save_finished_game(game).await.unwrap();
+                }
+                let games = storage.finished_games(2).await.unwrap();
+                let rooms: Vec<_> = games.iter().map(|g| g.room_id.as_str()).collect();
+                assert_eq!(rooms, ["C", "B"]);
+            }
+        }
+    }
+}
+
+mod config {
+    use std::{
+        fmt::Display,
+        io::ErrorKind,
+        net::{IpAddr, SocketAddr},
+        path::PathBuf,
+        str::FromStr,
+        time::Duration,
+    };
+
+    use anyhow::{anyhow, bail, Context, Result};
+    use common::RoomSettings;
+    use serde::{Deserialize, Serialize};
+
+    const DEFAULT_CONFIG_PATH: &str = "pixel-skribbl.toml";
+
+    /// Server settings, read from `pixel-skribbl.toml` (or the file in `CONFIG_PATH`),
+    /// with environment variables taking precedence
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        pub network: NetworkConfig,
+        pub security: SecurityConfig,
+        pub limits: LimitsConfig,
+        /// Settings that new rooms start out with
+        pub room_defaults: RoomSettings,
+        pub storage: StorageConfig,
+        pub logging: LoggingConfig,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct NetworkConfig {
+        /// Only used by the standalone server, Shuttle picks its own
+        pub bind_address: SocketAddr,
+        /// Directory with the built frontend
+        pub static_dir: PathBuf,
+        /// Events buffered per room and stream before slow clients start missing them
+        pub channel_capacity: usize,
+        /// Reverse proxies whose `X-Forwarded-For` header is believed. Without any, clients
+        /// are told apart by the address they connect from
+        pub trusted_proxies: Vec<IpAddr>,
+    }
+    impl Default for NetworkConfig {
+        fn default() -> Self {
+            Self {
+                bind_address: SocketAddr::from(([0, 0, 0, 0], 8000)),
+                static_dir: "frontend/dist".into(),
+                channel_capacity: 128,
+                trusted_proxies: vec![],
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct SecurityConfig {
+        /// Only send the session cookie over HTTPS, on by default when running on Shuttle
+        pub secure_cookies: Option<bool>,
+        /// Bearer token for the admin endpoints, which are disabled without one
+        #[serde(skip_serializing)]
+        pub admin_token: Option<String>,
+        /// Failed joins allowed per client before they have to wait out the window
+        pub max_failed_joins: u32,
+        pub failed_join_window_secs: u64,
+        /// Origins other than our own `Host` that may send requests, like
+        /// `https://skribbl.example.com` when a reverse proxy rewrites the `Host` header
+        pub allowed_origins: Vec<String>,
+    }
+    impl Default for SecurityConfig {
+        fn default() -> Self {
+            Self {
+                secure_cookies: None,
+                admin_token: None,
+                max_failed_joins: 10,
+                failed_join_window_secs: 60,
+                allowed_origins: vec![],
+            }
+        }
+    }
+    impl SecurityConfig {
+        pub fn failed_join_window(&self) -> Duration {
+            Duration::from_secs(self.failed_join_window_secs)
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct LimitsConfig {
+        /// How long a room can go without connections before it is removed
+        pub room_idle_timeout_secs: u64,
+        /// Limits per address are this many times the ones per player, so players
+        /// sharing a connection don't slow each other down
+        pub players_per_ip: u32,
+        pub pixels: RateLimit,
+        pub clears: RateLimit,
+        pub chat: RateLimit,
+        pub room_creation: RateLimit,
+    }
+    impl Default for LimitsConfig {
+        fn default() -> Self {
+            Self {
+                room_idle_timeout_secs: 10 * 60,
+                players_per_ip: 4,
+                pixels: RateLimit {
+                    burst: 60,
+                    per_second: 20.0,
+                },
+                clears: RateLimit {
+                    burst: 3,
+                    per_second: 0.2,
+                },
+                chat: RateLimit {
+                    burst: 5,
+                    per_second: 1.0,
+                },
+                room_creation: RateLimit {
+                    burst: 3,
+                    per_second: 0.05,
+                },
+            }
+        }
+    }
+
+    /// A token bucket: `burst` actions at once, refilled at `per_second`
+    #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct RateLimit {
+        pub burst: u32,
+        pub per_second: f64,
+    }
+    impl LimitsConfig {
+        pub fn room_idle_timeout(&self) -> Duration {
+            Duration::from_secs(self.room_idle_timeout_secs)
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct StorageConfig {
+        /// SQLite database for rooms, sessions and history, only used by the standalone server
+        pub database_path: PathBuf,
+    }
+    impl Default for StorageConfig {
+        fn default() -> Self {
+            Self {
+                database_path: "pixel-skribbl.db".into(),
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct LoggingConfig {
+        /// Log filter, e.g. `debug` or `backend=debug,info`
+        pub level: String,
+        pub format: LogFormat,
+    }
+    impl Default for LoggingConfig {
+        fn default() -> Self {
+            Self {
+                level: "info".into(),
+                format: LogFormat::default(),
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LogFormat {
+        /// Human readable lines
+        #[default]
+        Pretty,
+        /// One JSON object per line, for log collectors
+        Json,
+    }
+    impl FromStr for LogFormat {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "pretty" => Ok(Self::Pretty),
+                "json" => Ok(Self::Json),
+                _ => Err(format!("expected pretty or json, got {s}")),
+            }
+        }
+    }
+
+    fn env_override<T: FromStr>(name: &str, value: &mut T) -> Result<()>
+    where
+        T::Err: Display,
+    {
+        if let Ok(var) = std::env::var(name) {
+            *value = var.parse().map_err(|e| anyhow!("Invalid {name}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    impl Config {
+        /// Reads the config file if there is one, applies the environment and validates the result
+        pub fn load() -> Result<Self> {
+            let path = std::env::var("CONFIG_PATH").ok();
+            let mut config =
+                match std::fs::read_to_string(path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)) {
+                    Ok(text) => toml::from_str(&text).context("Invalid config file")?,
+                    // The default file is optional, one that was asked for is not
+                    Err(e) if e.kind() == ErrorKind::NotFound && path.is_none() => {
+                        Config::default()
                     }
+                    Err(e) => return Err(e).context("Could not read config file"),
+                };
+            config.apply_env()?;
+            config.validate()?;
+            Ok(config)
+        }
+        fn apply_env(&mut self) -> Result<()> {
+            env_override("BIND_ADDRESS", &mut self.network.bind_address)?;
+            env_override("STATIC_DIR", &mut self.network.static_dir)?;
+            env_override("CHANNEL_CAPACITY", &mut self.network.channel_capacity)?;
+            if let Ok(var) = std::env::var("TRUSTED_PROXIES") {
+                self.network.trusted_proxies = var
+                    .split(',')
+                    .filter(|ip| !ip.trim().is_empty())
+                    .map(|ip| ip.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|e| anyhow!("Invalid TRUSTED_PROXIES: {e}"))?;
+            }
+            if let Ok(var) = std::env::var("SECURE_COOKIES") {
+                let secure = var
+                    .parse()
+                    .map_err(|e| anyhow!("Invalid SECURE_COOKIES: {e}"))?;
+                self.security.secure_cookies = Some(secure);
+            }
+            if let Ok(token) = std::env::var("ADMIN_TOKEN") {
+                self.security.admin_token = Some(token);
+            }
+            if let Ok(var) = std::env::var("ALLOWED_ORIGINS") {
+                self.security.allowed_origins = var
+                    .split(',')
+                    .map(|origin| origin.trim().to_owned())
+                    .filter(|origin| !origin.is_empty())
+                    .collect();
+            }
+            env_override(
+                "ROOM_IDLE_TIMEOUT_SECS",
+                &mut self.limits.room_idle_timeout_secs,
+            )?;
+            env_override("DATABASE_PATH", &mut self.storage.database_path)?;
+            env_override("LOG_LEVEL", &mut self.logging.level)?;
+            env_override("LOG_FORMAT", &mut self.logging.format)?;
+            Ok(())
+        }
+        fn validate(&self) -> Result<()> {
+            if self.network.channel_capacity == 0 {
+                bail!("network.channel_capacity must be at least 1");
+            }
+            if self
+                .security
+                .admin_token
+                .as_deref()
+                .is_some_and(str::is_empty)
+            {
+                bail!("security.admin_token must not be empty");
+            }
+            // Browsers send the origin without a path, so anything else would never match
+            if let Some(origin) = self.security.allowed_origins.iter().find(|origin| {
+                origin
+                    .split_once("://")
+                    .is_none_or(|(_, host)| host.is_empty() || host.contains('/'))
+            }) {
+                bail!(
+                    "security.allowed_origins needs origins like https://example.com, got {origin}"
+                );
+            }
+            if self.security.max_failed_joins == 0 || self.security.failed_join_window_secs == 0 {
+                bail!("security.max_failed_joins and security.failed_join_window_secs must be at least 1");
+            }
+            if self.limits.room_idle_timeout_secs == 0 {
+                bail!("limits.room_idle_timeout_secs must be at least 1");
+            }
+            if self.limits.players_per_ip == 0 {
+                bail!("limits.players_per_ip must be at least 1");
+            }
+            let rate_limits = [
+                ("pixels", self.limits.pixels),
+                ("clears", self.limits.clears),
+                ("chat", self.limits.chat),
+                ("room_creation", self.limits.room_creation),
+            ];
+            for (name, limit) in rate_limits {
+                if limit.burst == 0 || !limit.per_second.is_finite() || limit.per_second <= 0.0 {
+                    bail!("limits.{name} needs a burst of at least 1 and a positive per_second");
+                }
+            }
+            self.room_defaults
+                .validate()
+                .map_err(|e| anyhow!("Invalid room_defaults: {e}"))?;
+            tracing_subscriber::EnvFilter::try_new(&self.logging.level)
+                .context("Invalid logging.level")?;
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn example_config_is_the_default() {
+            let example: Config =
+                toml::from_str(include_str!("../../pixel-skribbl.example.toml")).unwrap();
+            example.validate().unwrap();
+            assert_eq!(
+                toml::to_string(&example).unwrap(),
+                toml::to_string(&Config::default()).unwrap()
+            );
+        }
+
+        #[test]
+        fn unknown_and_invalid_settings_are_rejected() {
+            assert!(toml::from_str::<Config>("[network]\nbind_adress = \"0.0.0.0:80\"").is_err());
+            let invalid: [fn(&mut Config); 5] = [
+                |c| c.network.channel_capacity = 0,
+                |c| c.security.admin_token = Some(String::new()),
+                |c| c.security.allowed_origins = vec!["https://example.com/".into()],
+                |c| c.limits.room_idle_timeout_secs = 0,
+                |c| c.room_defaults.canvas_width = 0,
+            ];
+            for change in invalid {
+                let mut config = Config::default();
+                change(&mut config);
+                assert!(config.validate().is_err());
+            }
+        }
+
+        #[test]
+        fn environment_overrides_the_file() {
+            let mut config: Config = toml::from_str("[network]\nchannel_capacity = 8").unwrap();
+            std::env::set_var("CHANNEL_CAPACITY", "32");
+            std::env::set_var("TRUSTED_PROXIES", "10.0.0.1, 10.0.0.2");
+            std::env::set_var("ALLOWED_ORIGINS", "https://a.example,https://b.example");
+            let applied = config.apply_env();
+            std::env::set_var("CHANNEL_CAPACITY", "lots");
+            let invalid = config.clone().apply_env();
+            std::env::remove_var("CHANNEL_CAPACITY");
+            std::env::remove_var("TRUSTED_PROXIES");
+            std::env::remove_var("ALLOWED_ORIGINS");
+            applied.unwrap();
+            assert_eq!(config.network.channel_capacity, 32);
+            assert_eq!(config.network.trusted_proxies.len(), 2);
+            assert_eq!(config.security.allowed_origins.len(), 2);
+            assert!(invalid.is_err());
+        }
+    }
+}
+
+mod metrics {
+    use prometheus::{
+        histogram_opts, opts, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+        IntGaugeVec, Registry, TextEncoder,
+    };
+
+    const NAMESPACE: &str = "pixel_skribbl";
+
+    /// Everything exported on `/metrics`. The room and player gauges are filled in
+    /// right before each scrape, the rest as things happen
+    #[derive(Clone)]
+    pub struct Metrics {
+        registry: Registry,
+        pub rooms: IntGauge,
+        pub players: IntGauge,
+        pub spectators: IntGauge,
+        /// Open WebSockets per stream
+        pub sockets: IntGaugeVec,
+        /// Chat messages that counted as guesses, by whether they were right
+        pub guesses: IntCounterVec,
+        /// Turns that ended, by guess or by running out of time
+        pub rounds: IntCounter,
+        pub pixels_set: IntCounter,
+        pub canvas_clears: IntCounter,
+        /// Broadcasts a socket missed because it fell too far behind, per stream
+        pub broadcast_lag: IntCounterVec,
+        /// Requests turned away by the rate limits, per action and whether the player
+        /// or their address ran out
+        pub throttled: IntCounterVec,
+        pub handler_latency: HistogramVec,
+        pub frame_size: HistogramVec,
+    }
+    impl Default for Metrics {
+        fn default() -> Self {
+            let metrics = Self {
+                registry: Registry::new(),
+                rooms: IntGauge::with_opts(
+                    opts!("rooms", "Rooms currently open").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                players: IntGauge::with_opts(
+                    opts!("players", "Players in all rooms").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                spectators: IntGauge::with_opts(
+                    opts!("spectators", "Spectators in all rooms").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                sockets: IntGaugeVec::new(
+                    opts!("websockets", "Open WebSocket connections").namespace(NAMESPACE),
+                    &["stream"],
+                )
+                .unwrap(),
+                guesses: IntCounterVec::new(
+                    opts!("guesses_total", "Guesses made in chat").namespace(NAMESPACE),
+                    &["correct"],
+                )
+                .unwrap(),
+                rounds: IntCounter::with_opts(
+                    opts!("rounds_total", "Turns played to the end").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                pixels_set: IntCounter::with_opts(
+                    opts!("pixels_set_total", "Pixels painted").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                canvas_clears: IntCounter::with_opts(
+                    opts!("canvas_clears_total", "Canvases cleared").namespace(NAMESPACE),
+                )
+                .unwrap(),
+                broadcast_lag: IntCounterVec::new(
+                    opts!(
+                        "broadcast_lagged_total",
+                        "Broadcasts skipped by sockets that fell behind"
+                    )
+                    .namespace(NAMESPACE),
+                    &["stream"],
+                )
+                .unwrap(),
+                throttled: IntCounterVec::new(
+                    opts!("throttled_total", "Requests rejected by rate limits")
+                        .namespace(NAMESPACE),
+                    &["action", "scope"],
+                )
+                .unwrap(),
+                handler_latency: HistogramVec::new(
+                    histogram_opts!(
+                        "handler_duration_seconds",
+                        "Time spent handling HTTP requests"
+                    )
+                    .namespace(NAMESPACE),
+                    &["method", "path"],
+                )
+                .unwrap(),
+                frame_size: HistogramVec::new(
+                    histogram_opts!(
+                        "frame_size_bytes",
+                        "Size of the frames sent on WebSockets",
+                        prometheus::exponential_buckets(64.0, 4.0, 8).unwrap()
+                    )
+                    .namespace(NAMESPACE),
+                    &["stream"],
+                )
+                .unwrap(),
+            };
+            let collectors: [Box<dyn prometheus::core::Collector>; 12] = [
+                Box::new(metrics.rooms.clone()),
+                Box::new(metrics.players.clone()),
+                Box::new(metrics.spectators.clone()),
+                Box::new(metrics.sockets.clone()),
+                Box::new(metrics.guesses.clone()),
+                Box::new(metrics.rounds.clone()),
+                Box::new(metrics.pixels_set.clone()),
+                Box::new(metrics.canvas_clears.clone()),
+                Box::new(metrics.broadcast_lag.clone()),
+                Box::new(metrics.throttled.clone()),
+                Box::new(metrics.handler_latency.clone()),
+                Box::new(metrics.frame_size.clone()),
+            ];
+            for collector in collectors {
+                metrics.registry.register(collector).unwrap();
+            }
+            metrics
+        }
+    }
+    impl Metrics {
+        /// The Prometheus text format
+        pub fn render(&self) -> String {
+            let mut buf = vec![];
+            TextEncoder::new()
+                .encode(&self.registry.gather(), &mut buf)
+                .unwrap();
+            String::from_utf8(buf).unwrap()
+        }
+    }
+}
+
+mod rate_limit {
+    use std::{
+        collections::HashMap,
+        hash::Hash,
+        marker::PhantomData,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use axum::{
+        async_trait,
+        extract::FromRequestParts,
+        http::{header::RETRY_AFTER, request::Parts, StatusCode},
+        response::{IntoResponse, Response},
+    };
+    use common::SessionPlayer;
+    use tower_sessions::Session;
+    use tracing::debug;
+
+    use crate::{
+        config::{LimitsConfig, RateLimit},
+        AppState, ClientIp,
+    };
+
+    /// Token buckets per key, that refill continuously up to the burst size
+    struct Buckets<K> {
+        limit: RateLimit,
+        buckets: Mutex<HashMap<K, (f64, Instant)>>,
+    }
+    impl<K: Hash + Eq> Buckets<K> {
+        fn new(limit: RateLimit) -> Self {
+            Self {
+                limit,
+                buckets: Default::default(),
+            }
+        }
+        /// Takes a token, or says how long until the next one
+        fn take(&self, key: K) -> Result<(), Duration> {
+            let burst = self.limit.burst as f64;
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock().unwrap();
+            let (tokens, updated) = buckets.entry(key).or_insert((burst, now));
+            *tokens = (*tokens + (now - *updated).as_secs_f64() * self.limit.per_second).min(burst);
+            *updated = now;
+            if *tokens >= 1.0 {
+                *tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64(
+                    (1.0 - *tokens) / self.limit.per_second,
+                ))
+            }
+        }
+        /// Drops buckets that have filled up again, they are the same as new ones
+        fn sweep(&self) {
+            let burst = self.limit.burst as f64;
+            self.buckets.lock().unwrap().retain(|_, (tokens, updated)| {
+                *tokens + updated.elapsed().as_secs_f64() * self.limit.per_second < burst
+            });
+        }
+    }
+
+    /// Limits an action per player, and per address so new sessions don't get around it
+    pub struct ActionLimit {
+        players: Buckets<(String, String)>,
+        ips: Buckets<IpAddr>,
+    }
+    impl ActionLimit {
+        fn new(limit: RateLimit, players_per_ip: u32) -> Self {
+            Self {
+                players: Buckets::new(limit),
+                ips: Buckets::new(RateLimit {
+                    burst: limit.burst * players_per_ip,
+                    per_second: limit.per_second * players_per_ip as f64,
+                }),
+            }
+        }
+        fn take(&self, player: Option<&SessionPlayer>, ip: IpAddr) -> Result<(), Throttled> {
+            if let Some(player) = player {
+                self.players
+                    .take((player.room.clone(), player.username.clone()))
+                    .map_err(|retry_after| Throttled {
+                        retry_after,
+                        scope: "player",
+                    })?;
+            }
+            self.ips.take(ip).map_err(|retry_after| Throttled {
+                retry_after,
+                scope: "ip",
+            })
+        }
+        fn sweep(&self) {
+            self.players.sweep();
+            self.ips.sweep();
+        }
+    }
+
+    pub struct RateLimits {
+        pub pixels: ActionLimit,
+        pub clears: ActionLimit,
+        pub chat: ActionLimit,
+        pub room_creation: ActionLimit,
+    }
+    impl RateLimits {
+        pub fn new(config: &LimitsConfig) -> Self {
+            Self {
+                pixels: ActionLimit::new(config.pixels, config.players_per_ip),
+                clears: ActionLimit::new(config.clears, config.players_per_ip),
+                chat: ActionLimit::new(config.chat, config.players_per_ip),
+                room_creation: ActionLimit::new(config.room_creation, config.players_per_ip),
+            }
+        }
+        pub fn sweep(&self) {
+            self.pixels.sweep();
+            self.clears.sweep();
+            self.chat.sweep();
+            self.room_creation.sweep();
+        }
+    }
+
+    /// Rejection for requests over the limit
+    pub struct Throttled {
+        retry_after: Duration,
+        scope: &'static str,
+    }
+    impl IntoResponse for Throttled {
+        fn into_response(self) -> Response {
+            let secs = self.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, secs.to_string())],
+                "Slow down!",
+            )
+                .into_response()
+        }
+    }
+
+    pub trait Action {
+        const NAME: &'static str;
+        fn limit(limits: &RateLimits) -> &ActionLimit;
+    }
+    macro_rules! action {
+        ($name:ident, $field:ident) => {
+            pub struct $name;
+            impl Action for $name {
+                const NAME: &'static str = stringify!($field);
+                fn limit(limits: &RateLimits) -> &ActionLimit {
+                    &limits.$field
                 }
             }
         };
     }
+    action!(Pixels, pixels);
+    action!(Clears, clears);
+    action!(Chat, chat);
+    action!(RoomCreation, room_creation);
+
+    /// Extracting this takes a token for `A`, the request is rejected when there is none
+    pub struct Throttle<A>(PhantomData<A>);
+    #[async_trait]
+    impl<A: Action> FromRequestParts<Arc<AppState>> for Throttle<A> {
+        type Rejection = Throttled;
+
+        async fn from_request_parts(
+            parts: &mut Parts,
+            state: &Arc<AppState>,
+        ) -> Result<Self, Self::Rejection> {
+            let Ok(ClientIp(ip)) = ClientIp::from_request_parts(parts, state).await;
+            let player = match Session::from_request_parts(parts, state).await {
+                Ok(session) => session.get::<SessionPlayer>("user").await.ok().flatten(),
+                Err(_) => None,
+            };
+            A::limit(&state.rate_limits)
+                .take(player.as_ref(), ip)
+                .inspect_err(|throttled| {
+                    debug!(action = A::NAME, scope = throttled.scope, "Throttled");
+                    state
+                        .metrics
+                        .throttled
+                        .with_label_values(&[A::NAME, throttled.scope])
+                        .inc();
+                })?;
+            Ok(Self(PhantomData))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use axum::http::Request;
+
+        use super::*;
+        use crate::{config::Config, storage::MemoryStorage};
+
+        const LIMIT: RateLimit = RateLimit {
+            burst: 2,
+            per_second: 1.0,
+        };
+
+        fn player(username: &str) -> SessionPlayer {
+            SessionPlayer {
+                username: username.into(),
+                room: "BAKOTU".into(),
+                spectator: false,
+            }
+        }
+
+        #[test]
+        fn buckets_allow_a_burst_then_refill() {
+            let buckets = Buckets::new(LIMIT);
+            assert!(buckets.take("a").is_ok() && buckets.take("a").is_ok());
+            let retry_after = buckets.take("a").unwrap_err();
+            assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+            // Other keys have their own bucket
+            assert!(buckets.take("b").is_ok());
+            buckets.buckets.lock().unwrap().get_mut("a").unwrap().1 -= Duration::from_secs(1);
+            assert!(buckets.take("a").is_ok());
+        }
+
+        #[test]
+        fn sweep_only_drops_full_buckets() {
+            let buckets = Buckets::new(LIMIT);
+            buckets.take("a").unwrap();
+            buckets.take("b").unwrap();
+            buckets.buckets.lock().unwrap().get_mut("b").unwrap().1 -= Duration::from_secs(1);
+            buckets.sweep();
+            let left: Vec<_> = buckets.buckets.lock().unwrap().keys().copied().collect();
+            assert_eq!(left, ["a"]);
+        }
+
+        #[test]
+        fn new_players_on_one_address_share_its_limit() {
+            let limit = ActionLimit::new(LIMIT, 2);
+            let ip = "198.51.100.7".parse().unwrap();
+            for name in ["a", "b"] {
+                assert!(limit.take(Some(&player(name)), ip).is_ok());
+                assert!(limit.take(Some(&player(name)), ip).is_ok());
+                let throttled = limit.take(Some(&player(name)), ip).err().unwrap();
+                assert_eq!(throttled.scope, "player");
+            }
+            let throttled = limit.take(Some(&player("c")), ip).err().unwrap();
+            assert_eq!(throttled.scope, "ip");
+            assert!(limit.take(None, "198.51.100.8".parse().unwrap()).is_ok());
+        }
+
+        #[tokio::test]
+        async fn throttled_requests_get_retry_after() {
+            let mut config = Config::default();
+            config.limits.chat = LIMIT;
+            config.limits.players_per_ip = 1;
+            let state = Arc::new(AppState::new(Arc::new(MemoryStorage::default()), config));
+            let mut statuses = vec![];
+            for _ in 0..3 {
+                let (mut parts, _) = Request::new(()).into_parts();
+                statuses.push(
+                    match Throttle::<Chat>::from_request_parts(&mut parts, &state).await {
+                        Ok(_) => StatusCode::OK.into_response(),
+                        Err(throttled) => throttled.into_response(),
+                    },
+                );
+            }
+            let codes: Vec<_> = statuses.iter().map(|r| r.status()).collect();
+            assert_eq!(
+                codes,
+                [
+                    StatusCode::OK,
+                    StatusCode::OK,
+                    StatusCode::TOO_MANY_REQUESTS
+                ]
+            );
+            assert_eq!(statuses[2].headers()[RETRY_AFTER], "1");
+            let throttled = state.metrics.throttled.with_label_values(&["chat", "ip"]);
+            assert_eq!(throttled.get(), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(trusted_proxies: &[&str]) -> Arc<AppState> {
+        let mut config = Config::default();
+        config.network.trusted_proxies =
+            trusted_proxies.iter().map(|p| p.parse().unwrap()).collect();
+        Arc::new(AppState::new(Arc::new(MemoryStorage::default()), config))
+    }
+    async fn client_ip(state: &Arc<AppState>, peer: Option<&str>, forwarded: &str) -> IpAddr {
+        let mut req = Request::builder().header("x-forwarded-for", forwarded);
+        if let Some(peer) = peer {
+            req = req.extension(ConnectInfo(SocketAddr::new(peer.parse().unwrap(), 1234)));
+        }
+        let (mut parts, _) = req.body(()).unwrap().into_parts();
+        let Ok(ClientIp(ip)) = ClientIp::from_request_parts(&mut parts, state).await;
+        ip
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let state = state(&[]);
+        let ip = client_ip(&state, Some("203.0.113.1"), "198.51.100.7").await;
+        assert_eq!(ip, "203.0.113.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn forwarded_for_skips_spoofed_and_proxy_entries() {
+        let state = state(&["10.0.0.1", "10.0.0.2"]);
+        let ip = client_ip(&state, Some("10.0.0.1"), "1.2.3.4, 198.51.100.7, 10.0.0.2").await;
+        assert_eq!(ip, "198.51.100.7".parse::<IpAddr>().unwrap());
+        // Without a peer address only Shuttle's proxy can have sent the request
+        let ip = client_ip(&state, None, "198.51.100.8").await;
+        assert_eq!(ip, "198.51.100.8".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn failed_joins_lock_out_until_the_window_passes() {
+        let state = state(&[]);
+        let ip = "198.51.100.7".parse().unwrap();
+        for _ in 0..state.config.security.max_failed_joins {
+            assert!(!state.join_locked_out(ip).await);
+            state.record_failed_join(ip).await;
+        }
+        assert!(state.join_locked_out(ip).await);
+        assert!(!state.join_locked_out("198.51.100.8".parse().unwrap()).await);
+        state.failed_joins.lock().await.get_mut(&ip).unwrap().1 -=
+            state.config.security.failed_join_window() + Duration::from_secs(1);
+        assert!(!state.join_locked_out(ip).await);
+    }
+
+    #[tokio::test]
+    async fn room_passwords_are_checked() {
+        let state = state(&[]);
+        let code = state
+            .create_room(RoomSettings::default(), "a".into(), Some("hunter2".into()))
+            .await;
+        let room = state.rooms.read().await[&code].clone();
+        assert!(room.check_password(Some("hunter2".into())).await);
+        assert!(!room.check_password(Some("hunter3".into())).await);
+        assert!(!room.check_password(None).await);
+    }
+
+    #[tokio::test]
+    async fn rooms_come_back_after_a_restart() {
+        let storage = Arc::new(MemoryStorage::default());
+        let state = Arc::new(AppState::new(storage.clone(), Config::default()));
+        let code = state
+            .create_room(RoomSettings::default(), "a".into(), None)
+            .await;
+        state.rooms.read().await[&code]
+            .game_state
+            .write()
+            .await
+            .add_player(Player::new("a".into()), false);
+        state.shutdown().await;
+        assert!(state.shutting_down.load(Ordering::SeqCst));
+
+        let restarted = Arc::new(AppState::new(storage, Config::default()));
+        restarted.restore_rooms().await.unwrap();
+        let room = restarted.rooms.read().await[&code].clone();
+        assert_eq!(room.game_state.read().await.players.len(), 1);
+    }
+
+    async fn admin(token: Option<&str>, header: Option<&str>) -> Result<(), StatusCode> {
+        let mut config = Config::default();
+        config.security.admin_token = token.map(Into::into);
+        let state = Arc::new(AppState::new(Arc::new(MemoryStorage::default()), config));
+        let mut req = Request::builder();
+        if let Some(header) = header {
+            req = req.header(AUTHORIZATION, header);
+        }
+        let (mut parts, _) = req.body(()).unwrap().into_parts();
+        Admin::from_request_parts(&mut parts, &state)
+            .await
+            .map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn admin_api_needs_the_token() {
+        assert_eq!(admin(Some("secret"), Some("Bearer secret")).await, Ok(()));
+        for header in [
+            None,
+            Some("Bearer wrong"),
+            Some("Bearer secre"),
+            Some("secret"),
+        ] {
+            assert_eq!(
+                admin(Some("secret"), header).await,
+                Err(StatusCode::UNAUTHORIZED)
+            );
+        }
+        // Without a token the admin API doesn't exist
+        assert_eq!(
+            admin(None, Some("Bearer ")).await,
+            Err(StatusCode::NOT_FOUND)
+        );
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_shutting_down() {
+        let state = state(&[]);
+        assert_eq!(readyz(State(state.clone())).await, StatusCode::OK);
+        state.shutdown().await;
+        assert_eq!(readyz(State(state)).await, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    async fn origin_check(
+        allowed_origins: &[&str],
+        method: Method,
+        path: &str,
+        origin: Option<&str>,
+    ) -> StatusCode {
+        use tower::ServiceExt;
+
+        let mut config = Config::default();
+        config.security.allowed_origins = allowed_origins.iter().map(|o| o.to_string()).collect();
+        let state = Arc::new(AppState::new(Arc::new(MemoryStorage::default()), config));
+        let app = Router::new()
+            .fallback(|| async { StatusCode::OK })
+            .layer(middleware::from_fn_with_state(state.clone(), check_origin))
+            .with_state(state);
+        let mut req = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(HOST, "internal:8000");
+        if let Some(origin) = origin {
+            req = req.header(ORIGIN, origin);
+        }
+        app.oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn cross_site_requests_are_refused() {
+        let other = Some("https://evil.example");
+        assert_eq!(
+            origin_check(&[], Method::POST, "/api/chat", other).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            origin_check(&[], Method::GET, "/ws/BAKOTU/chat", other).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            origin_check(&[], Method::GET, "/api/rooms", other).await,
+            StatusCode::OK
+        );
+        // Same site, and clients that aren't browsers
+        let own = Some("http://internal:8000");
+        assert_eq!(
+            origin_check(&[], Method::POST, "/api/chat", own).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            origin_check(&[], Method::POST, "/api/chat", None).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn allowed_origins_get_through_a_rewritten_host() {
+        let public = ["https://skribbl.example"];
+        assert_eq!(
+            origin_check(&public, Method::POST, "/api/chat", Some(public[0])).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            origin_check(
+                &public,
+                Method::POST,
+                "/api/chat",
+                Some("http://skribbl.example")
+            )
+            .await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_count_rooms_and_players_when_scraped() {
+        let state = state(&[]);
+        let code = state
+            .create_room(RoomSettings::default(), "a".into(), None)
+            .await;
+        {
+            let room = state.rooms.read().await[&code].clone();
+            let mut gs = room.game_state.write().await;
+            gs.add_player(Player::new("a".into()), false);
+            gs.add_player(Player::new("s".into()), true);
+        }
+        state.metrics.rounds.inc();
+        let body = metrics_handler(State(state))
+            .await
+            .into_response()
+            .into_body();
+        let text = String::from_utf8(
+            axum::body::to_bytes(body, usize::MAX)
+                .await
+                .unwrap()
+                .to_vec(),
+        )
+        .unwrap();
+        for line in [
+            "pixel_skribbl_rooms 1",
+            "pixel_skribbl_players 1",
+            "pixel_skribbl_spectators 1",
+            "pixel_skribbl_rounds_total 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line} missing from {text}"
+            );
+        }
+    }
 }
@@ -3,113 +3,1263 @@ use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumIter};
 
-#[derive(Debug, Display, Clone, Copy, PartialEq, EnumIter, Serialize, Deserialize)]
-pub enum Color {
-    Red,
-    Orange,
-    Yellow,
-    Lime,
-    Green,
-    Blue,
-    Cyan,
-    Magenta,
-    Purple,
-    Black,
-    Gray,
-    White,
-}
-impl Default for Color {
+/// A color as its index into the room's palette. The first color is the background
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Color(pub u8);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+impl Rgb {
+    pub const fn hex(hex: u32) -> Self {
+        Self((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
+    }
+    pub fn css(self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+    /// Whether white text reads better on it than black
+    pub fn is_dark(self) -> bool {
+        let luma = 0.299 * self.0 as f64 + 0.587 * self.1 as f64 + 0.114 * self.2 as f64;
+        luma < 128.0
+    }
+    fn distance(self, other: Self) -> u32 {
+        [(self.0, other.0), (self.1, other.1), (self.2, other.2)]
+            .into_iter()
+            .map(|(a, b)| (a.abs_diff(b) as u32).pow(2))
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PaletteColor {
+    pub name: String,
+    pub rgb: Rgb,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub name: String,
+    pub colors: Vec<PaletteColor>,
+}
+impl Default for Palette {
     fn default() -> Self {
-        Self::White
+        Self::presets().swap_remove(0)
+    }
+}
+impl Palette {
+    fn preset(name: &str, colors: &[(&str, u32)]) -> Self {
+        Self {
+            name: name.into(),
+            colors: colors
+                .iter()
+                .map(|&(name, hex)| PaletteColor {
+                    name: name.into(),
+                    rgb: Rgb::hex(hex),
+                })
+                .collect(),
+        }
+    }
+    /// Built-in palettes the host can pick from, the first is the default
+    pub fn presets() -> Vec<Self> {
+        vec![
+            Self::preset(
+                "Classic",
+                &[
+                    ("White", 0xffffff),
+                    ("Gray", 0x808080),
+                    ("Black", 0x000000),
+                    ("Red", 0xff0000),
+                    ("Orange", 0xffa500),
+                    ("Yellow", 0xffff00),
+                    ("Lime", 0x00ff00),
+                    ("Green", 0x008000),
+                    ("Blue", 0x0000ff),
+                    ("Cyan", 0x00ffff),
+                    ("Magenta", 0xff00ff),
+                    ("Purple", 0x800080),
+                ],
+            ),
+            Self::preset(
+                "PICO-8",
+                &[
+                    ("White", 0xfff1e8),
+                    ("Black", 0x000000),
+                    ("Dark blue", 0x1d2b53),
+                    ("Dark purple", 0x7e2553),
+                    ("Dark green", 0x008751),
+                    ("Brown", 0xab5236),
+                    ("Dark gray", 0x5f574f),
+                    ("Light gray", 0xc2c3c7),
+                    ("Red", 0xff004d),
+                    ("Orange", 0xffa300),
+                    ("Yellow", 0xffec27),
+                    ("Green", 0x00e436),
+                    ("Blue", 0x29adff),
+                    ("Lavender", 0x83769c),
+                    ("Pink", 0xff77a8),
+                    ("Peach", 0xffccaa),
+                ],
+            ),
+            // Okabe and Ito's set, told apart with any kind of color blindness
+            Self::preset(
+                "Color-blind safe",
+                &[
+                    ("White", 0xffffff),
+                    ("Black", 0x000000),
+                    ("Orange", 0xe69f00),
+                    ("Sky blue", 0x56b4e9),
+                    ("Bluish green", 0x009e73),
+                    ("Yellow", 0xf0e442),
+                    ("Blue", 0x0072b2),
+                    ("Vermillion", 0xd55e00),
+                    ("Reddish purple", 0xcc79a7),
+                ],
+            ),
+            // Paul Tol's bright scheme
+            Self::preset(
+                "Tol bright",
+                &[
+                    ("White", 0xffffff),
+                    ("Black", 0x000000),
+                    ("Blue", 0x4477aa),
+                    ("Cyan", 0x66ccee),
+                    ("Green", 0x228833),
+                    ("Yellow", 0xccbb44),
+                    ("Red", 0xee6677),
+                    ("Purple", 0xaa3377),
+                    ("Grey", 0xbbbbbb),
+                ],
+            ),
+            Self::preset(
+                "Game Boy",
+                &[
+                    ("Lightest", 0x9bbc0f),
+                    ("Light", 0x8bac0f),
+                    ("Dark", 0x306230),
+                    ("Darkest", 0x0f380f),
+                ],
+            ),
+            Self::preset(
+                "Greyscale",
+                &[
+                    ("White", 0xffffff),
+                    ("Grey 1", 0xdbdbdb),
+                    ("Grey 2", 0xb6b6b6),
+                    ("Grey 3", 0x929292),
+                    ("Grey 4", 0x6d6d6d),
+                    ("Grey 5", 0x494949),
+                    ("Grey 6", 0x242424),
+                    ("Black", 0x000000),
+                ],
+            ),
+        ]
+    }
+    pub fn get(&self, color: Color) -> Option<&PaletteColor> {
+        self.colors.get(color.0 as usize)
+    }
+    /// The background stands in for colors the palette doesn't have
+    pub fn rgb(&self, color: Color) -> Rgb {
+        self.get(color)
+            .or(self.colors.first())
+            .map_or(Rgb(255, 255, 255), |c| c.rgb)
+    }
+    pub fn css(&self, color: Color) -> String {
+        self.rgb(color).css()
+    }
+    /// Every color of the palette
+    pub fn indices(&self) -> impl Iterator<Item = Color> {
+        (0..self.colors.len()).map(|i| Color(i as u8))
+    }
+    /// The color that stands out most against the background, to draw with by default
+    pub fn ink(&self) -> Color {
+        let Some(background) = self.colors.first() else {
+            return Color::default();
+        };
+        self.indices()
+            .max_by_key(|&c| self.colors[c.0 as usize].rgb.distance(background.rgb))
+            .unwrap_or_default()
+    }
+    pub fn validate(&self) -> Result<(), String> {
+        if self.colors.is_empty() || self.colors.len() > MAX_PALETTE_SIZE {
+            return Err(format!(
+                "palette must have between 1 and {MAX_PALETTE_SIZE} colors"
+            ));
+        }
+        let too_long = |name: &str| name.trim().is_empty() || name.chars().count() > 32;
+        if too_long(&self.name) || self.colors.iter().any(|c| too_long(&c.name)) {
+            return Err("palette and color names must be 1 to 32 characters".into());
+        }
+        Ok(())
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+pub const MAX_PALETTE_SIZE: usize = 64;
+
+/// Row-major grid of `width * height` pixels, flattened from a stack of layers
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct DrawCanvas {
     pub width: usize,
     pub height: usize,
+    /// What the layers look like stacked, with transparent pixels showing the background
     pub grid: Vec<Color>,
+    /// Bottom layer first. Only sent to players who may draw, guessers just get `grid`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<Layer>,
+    /// Edits that can be undone, newest last. Only kept on the server
+    #[serde(skip)]
+    undo: Vec<Edit>,
+    #[serde(skip)]
+    redo: Vec<Edit>,
+    /// `(layer, index)` of the pixels changed since `take_delta`, None once more than pixels
+    /// changed
+    #[serde(skip)]
+    changes: Option<Vec<(usize, usize)>>,
 }
-impl Default for DrawCanvas {
-    fn default() -> Self {
+
+/// A canvas as it is sent to players, borrowed so the edit history isn't copied along.
+/// Reads back as a `DrawCanvas`
+#[derive(Serialize)]
+pub struct CanvasView<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub grid: &'a [Color],
+    #[serde(skip_serializing_if = "<[Layer]>::is_empty")]
+    pub layers: &'a [Layer],
+}
+
+/// What the canvas socket sends: the whole canvas on connecting and after changes to more
+/// than pixels, otherwise just the pixels that changed. Sent with a `CanvasView` and read
+/// back with a `DrawCanvas`
+#[derive(Serialize, Deserialize)]
+pub enum CanvasUpdate<C = DrawCanvas> {
+    Full(C),
+    Pixels(CanvasDelta),
+}
+
+/// Pixels a stroke, clear or undo changed, with their new colors
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CanvasDelta {
+    /// `(index, color)` in `grid`
+    pub grid: Vec<(usize, Color)>,
+    /// `(layer, index, pixel)`, only sent to players who get the layers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub layers: Vec<(usize, usize, Option<Color>)>,
+}
+
+/// One sheet of the canvas, `None` pixels let the layers below show through
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    pub visible: bool,
+    pub pixels: Vec<Option<Color>>,
+}
+
+impl Layer {
+    fn new(name: String, size: usize) -> Self {
         Self {
-            width: 12,
-            height: 12,
-            grid: vec![Color::default(); 12 * 12],
+            name,
+            visible: true,
+            pixels: vec![None; size],
         }
     }
 }
+
+#[derive(Clone, PartialEq)]
+enum Edit {
+    /// The pixels one stroke or clear changed, as `(layer, index, before, after)`
+    Pixels(Vec<(usize, usize, Option<Color>, Option<Color>)>),
+    /// An empty layer with this name was added on top
+    AddLayer(String),
+    /// A layer was taken out of the stack, kept to put it back
+    RemoveLayer {
+        index: usize,
+        layer: Layer,
+    },
+    MoveLayer {
+        from: usize,
+        to: usize,
+    },
+    /// A layer was shown or hidden
+    SetVisible {
+        layer: usize,
+        visible: bool,
+    },
+}
+
+/// Edits kept for undo per turn
+pub const MAX_UNDO: usize = 50;
+
+pub const MAX_LAYERS: usize = 8;
+
+impl Default for DrawCanvas {
+    fn default() -> Self {
+        let settings = RoomSettings::default();
+        Self::new(settings.canvas_width, settings.canvas_height)
+    }
+}
 impl DrawCanvas {
-    pub fn set_pixel(&mut self, i: usize, color: Color) {
-        self.grid[i] = color;
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            grid: vec![Color::default(); width * height],
+            layers: vec![Layer::new("Layer 1".into(), width * height)],
+            undo: vec![],
+            redo: vec![],
+            changes: Some(vec![]),
+        }
     }
+    /// Index into `grid`, or None if `(x, y)` is off the canvas
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then_some(y * self.width + x)
+    }
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Color> {
+        self.index(x, y).and_then(|i| self.grid.get(i).copied())
+    }
+    /// The same picture without its layers or history, for players who only look at it
+    pub fn flattened(&self) -> Self {
+        Self {
+            width: self.width,
+            height: self.height,
+            grid: self.grid.clone(),
+            layers: vec![],
+            undo: vec![],
+            redo: vec![],
+            changes: None,
+        }
+    }
+    /// Returns false and leaves the canvas alone if `(x, y)` is off the canvas or there is
+    /// no such layer
+    pub fn set_pixel(&mut self, layer: usize, x: usize, y: usize, color: Color) -> bool {
+        self.paint(layer, &[(x, y)], color)
+    }
+    /// Paints all of `pixels` on `layer` as one undoable edit or, if any is off the canvas,
+    /// none of them
+    pub fn paint(&mut self, layer: usize, pixels: &[(usize, usize)], color: Color) -> bool {
+        self.set_pixels(layer, pixels, Some(color))
+    }
+    /// Makes `pixels` on `layer` transparent again, otherwise like `paint`
+    pub fn erase(&mut self, layer: usize, pixels: &[(usize, usize)]) -> bool {
+        self.set_pixels(layer, pixels, None)
+    }
+    fn set_pixels(
+        &mut self,
+        layer: usize,
+        pixels: &[(usize, usize)],
+        color: Option<Color>,
+    ) -> bool {
+        self.ensure_layers();
+        let Some(indices) = pixels
+            .iter()
+            .map(|&(x, y)| self.index(x, y))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return false;
+        };
+        let Some(sheet) = self.layers.get_mut(layer) else {
+            return false;
+        };
+        let mut edit = vec![];
+        for i in indices {
+            if let Some(pixel) = sheet.pixels.get_mut(i).filter(|p| **p != color) {
+                edit.push((layer, i, *pixel, color));
+                *pixel = color;
+            }
+        }
+        self.record(Edit::Pixels(edit));
+        true
+    }
+    /// Every pixel a stroke through `points` covers with this brush and symmetry, clipped to
+    /// the canvas. None if any of the points is off the canvas
+    pub fn stamp(
+        &self,
+        points: &[(usize, usize)],
+        brush: Brush,
+        symmetry: Symmetry,
+    ) -> Option<Vec<(usize, usize)>> {
+        let mut seen = vec![false; self.width * self.height];
+        let mut pixels = vec![];
+        for &(x, y) in points {
+            self.index(x, y)?;
+            for (dx, dy) in brush.offsets() {
+                let (Some(x), Some(y)) = (x.checked_add_signed(dx), y.checked_add_signed(dy))
+                else {
+                    continue;
+                };
+                // The brush hangs over the edge, its mirror image would too
+                if self.index(x, y).is_none() {
+                    continue;
+                }
+                for p in symmetry.mirror((x, y), self.width, self.height) {
+                    if let Some(i) = self.index(p.0, p.1).filter(|&i| !seen[i]) {
+                        seen[i] = true;
+                        pixels.push(p);
+                    }
+                }
+            }
+        }
+        Some(pixels)
+    }
+    /// Paints the 4-connected area of one color around `(x, y)` onto `layer` as one undoable
+    /// edit. The area is found on the stacked layers, so an outline on one layer holds a fill
+    /// on another. Returns how many pixels the area has, or None if `(x, y)` is off the
+    /// canvas or there is no such layer
+    pub fn fill(&mut self, layer: usize, x: usize, y: usize, color: Color) -> Option<usize> {
+        self.ensure_layers();
+        self.layers.get(layer)?;
+        let target = self.get_pixel(x, y)?;
+        let mut pixels = vec![];
+        let mut seen = vec![false; self.width * self.height];
+        let mut todo = vec![(x, y)];
+        while let Some((x, y)) = todo.pop() {
+            let i = y * self.width + x;
+            if seen[i] || self.grid[i] != target {
+                continue;
+            }
+            seen[i] = true;
+            pixels.push((x, y));
+            if x > 0 {
+                todo.push((x - 1, y));
+            }
+            if x + 1 < self.width {
+                todo.push((x + 1, y));
+            }
+            if y > 0 {
+                todo.push((x, y - 1));
+            }
+            if y + 1 < self.height {
+                todo.push((x, y + 1));
+            }
+        }
+        self.paint(layer, &pixels, color);
+        Some(pixels.len())
+    }
+    /// Wipes every layer, which can be undone
     pub fn clear(&mut self) {
-        self.grid = vec![Color::default(); self.width * self.height];
+        self.ensure_layers();
+        let mut edit = vec![];
+        for (l, layer) in self.layers.iter_mut().enumerate() {
+            for (i, p) in layer.pixels.iter_mut().enumerate() {
+                if let Some(before) = p.take() {
+                    edit.push((l, i, Some(before), None));
+                }
+            }
+        }
+        self.record(Edit::Pixels(edit));
+    }
+    /// Adds an empty layer on top. Returns false if there are already `MAX_LAYERS`
+    pub fn add_layer(&mut self) -> bool {
+        self.ensure_layers();
+        if self.layers.len() >= MAX_LAYERS {
+            return false;
+        }
+        let name = format!("Layer {}", self.layers.len() + 1);
+        self.change_layers(Edit::AddLayer(name))
+    }
+    /// Returns false if there is no such layer or it is the only one
+    pub fn remove_layer(&mut self, layer: usize) -> bool {
+        self.ensure_layers();
+        if layer >= self.layers.len() || self.layers.len() == 1 {
+            return false;
+        }
+        let removed = self.layers[layer].clone();
+        self.change_layers(Edit::RemoveLayer {
+            index: layer,
+            layer: removed,
+        })
+    }
+    /// Moves a layer to another place in the stack, 0 being the bottom
+    pub fn move_layer(&mut self, from: usize, to: usize) -> bool {
+        self.ensure_layers();
+        if from >= self.layers.len() || to >= self.layers.len() {
+            return false;
+        }
+        if from != to {
+            self.change_layers(Edit::MoveLayer { from, to });
+        }
+        true
+    }
+    pub fn set_layer_visible(&mut self, layer: usize, visible: bool) -> bool {
+        self.ensure_layers();
+        if layer >= self.layers.len() {
+            return false;
+        }
+        if self.layers[layer].visible != visible {
+            self.change_layers(Edit::SetVisible { layer, visible });
+        }
+        true
+    }
+    /// Wipes the canvas and its history for a new turn
+    pub fn reset(&mut self) {
+        *self = Self::new(self.width, self.height);
+    }
+    /// Canvases saved before layers, and flattened copies, start over with `grid` as the
+    /// only layer
+    fn ensure_layers(&mut self) {
+        if self.layers.is_empty() {
+            let mut layer = Layer::new("Layer 1".into(), 0);
+            layer.pixels = self
+                .grid
+                .iter()
+                .map(|&c| (c != Color::default()).then_some(c))
+                .collect();
+            self.layers.push(layer);
+            self.changes = None;
+        }
+    }
+    fn change_layers(&mut self, edit: Edit) -> bool {
+        self.step_layers(&edit, false);
+        self.record(edit);
+        true
+    }
+    /// Does a change to the layers, or reverts it
+    fn step_layers(&mut self, edit: &Edit, undo: bool) {
+        match edit {
+            Edit::Pixels(_) => {}
+            Edit::AddLayer(_) if undo => {
+                self.layers.pop();
+            }
+            Edit::AddLayer(name) => {
+                let size = self.width * self.height;
+                self.layers.push(Layer::new(name.clone(), size));
+            }
+            Edit::RemoveLayer { index, layer } if undo => {
+                self.layers.insert(*index, layer.clone());
+            }
+            Edit::RemoveLayer { index, .. } => {
+                self.layers.remove(*index);
+            }
+            &Edit::MoveLayer { from, to } => {
+                let (from, to) = if undo { (to, from) } else { (from, to) };
+                let layer = self.layers.remove(from);
+                self.layers.insert(to, layer);
+            }
+            &Edit::SetVisible { layer, visible } => self.layers[layer].visible = visible != undo,
+        }
+    }
+    /// The pixels changed since the last call, to send instead of the whole canvas. None if
+    /// more than pixels changed, or so many of them that the whole canvas is about as big
+    pub fn take_delta(&mut self) -> Option<CanvasDelta> {
+        let mut changes = self.changes.replace(vec![])?;
+        changes.sort_unstable();
+        changes.dedup();
+        if changes.len() > self.grid.len() / 4 {
+            return None;
+        }
+        let mut indices: Vec<_> = changes.iter().map(|&(_, i)| i).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        Some(CanvasDelta {
+            grid: indices.into_iter().map(|i| (i, self.grid[i])).collect(),
+            layers: changes
+                .into_iter()
+                .map(|(l, i)| (l, i, self.layers[l].pixels[i]))
+                .collect(),
+        })
+    }
+    /// Patches a canvas that came from the server, skipping pixels it doesn't have
+    pub fn apply(&mut self, delta: &CanvasDelta) {
+        for &(i, color) in &delta.grid {
+            if let Some(pixel) = self.grid.get_mut(i) {
+                *pixel = color;
+            }
+        }
+        for &(l, i, color) in &delta.layers {
+            if let Some(pixel) = self.layers.get_mut(l).and_then(|l| l.pixels.get_mut(i)) {
+                *pixel = color;
+            }
+        }
+    }
+    /// Notes what an edit, or undoing it, changes for `take_delta`
+    fn note_changes(&mut self, edit: &Edit) {
+        match (edit, &mut self.changes) {
+            (Edit::Pixels(pixels), Some(changes)) => {
+                changes.extend(pixels.iter().map(|&(l, i, ..)| (l, i)))
+            }
+            _ => self.changes = None,
+        }
+    }
+    /// Stacks the visible layers into `grid`
+    fn flatten(&mut self) {
+        for (i, pixel) in self.grid.iter_mut().enumerate() {
+            *pixel = self
+                .layers
+                .iter()
+                .rev()
+                .filter(|l| l.visible)
+                .find_map(|l| l.pixels[i])
+                .unwrap_or_default();
+        }
+    }
+    fn record(&mut self, edit: Edit) {
+        if matches!(&edit, Edit::Pixels(pixels) if pixels.is_empty()) {
+            return;
+        }
+        if self.undo.len() == MAX_UNDO {
+            self.undo.remove(0);
+        }
+        self.note_changes(&edit);
+        self.undo.push(edit);
+        self.redo.clear();
+        self.flatten();
+    }
+    /// Reverts the newest edit. Returns whether there was one
+    pub fn undo(&mut self) -> bool {
+        let Some(edit) = self.undo.pop() else {
+            return false;
+        };
+        match &edit {
+            Edit::Pixels(pixels) => {
+                for &(l, i, before, _) in pixels.iter().rev() {
+                    self.layers[l].pixels[i] = before;
+                }
+            }
+            _ => self.step_layers(&edit, true),
+        }
+        self.note_changes(&edit);
+        self.redo.push(edit);
+        self.flatten();
+        true
+    }
+    /// Reapplies the newest undone edit. Returns whether there was one
+    pub fn redo(&mut self) -> bool {
+        let Some(edit) = self.redo.pop() else {
+            return false;
+        };
+        match &edit {
+            Edit::Pixels(pixels) => {
+                for &(l, i, _, after) in pixels {
+                    self.layers[l].pixels[i] = after;
+                }
+            }
+            _ => self.step_layers(&edit, false),
+        }
+        self.note_changes(&edit);
+        self.undo.push(edit);
+        self.flatten();
+        true
     }
 }
 
+/// Pixels on the straight line between two points, both ends included
+pub fn line(from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+    let (mut x, mut y) = (from.0 as isize, from.1 as isize);
+    let (x1, y1) = (to.0 as isize, to.1 as isize);
+    let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+    let (sx, sy) = ((x1 - x).signum(), (y1 - y).signum());
+    let mut err = dx + dy;
+    let mut pixels = vec![];
+    loop {
+        pixels.push((x as usize, y as usize));
+        if x == x1 && y == y1 {
+            return pixels;
+        }
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+}
+
+#[derive(Debug, Display, Clone, Copy, Default, PartialEq, EnumIter, Serialize, Deserialize)]
+pub enum Brush {
+    #[default]
+    #[strum(serialize = "1x1")]
+    Square1,
+    #[strum(serialize = "2x2")]
+    Square2,
+    #[strum(serialize = "3x3")]
+    Square3,
+    Round,
+}
+impl Brush {
+    /// Pixels painted around each point of a stroke
+    pub fn offsets(self) -> Vec<(isize, isize)> {
+        let (range, round) = match self {
+            Self::Square1 => (0..=0, false),
+            Self::Square2 => (0..=1, false),
+            Self::Square3 => (-1..=1, false),
+            Self::Round => (-2..=2, true),
+        };
+        range
+            .clone()
+            .flat_map(|dy| range.clone().map(move |dx| (dx, dy)))
+            .filter(|&(dx, dy)| !round || dx * dx + dy * dy <= 5)
+            .collect()
+    }
+}
+
+/// Mirrors everything the drawer paints
+#[derive(Debug, Display, Clone, Copy, Default, PartialEq, EnumIter, Serialize, Deserialize)]
+pub enum Symmetry {
+    #[default]
+    None,
+    /// Left and right halves mirror each other
+    Horizontal,
+    /// Top and bottom halves mirror each other
+    Vertical,
+    #[strum(serialize = "Four-way")]
+    FourWay,
+}
+impl Symmetry {
+    /// The pixel and its mirror images on a canvas of the given size, or nothing if the pixel
+    /// is off the canvas
+    pub fn mirror(
+        self,
+        (x, y): (usize, usize),
+        width: usize,
+        height: usize,
+    ) -> Vec<(usize, usize)> {
+        let (Some(mx), Some(my)) = (width.checked_sub(x + 1), height.checked_sub(y + 1)) else {
+            return vec![];
+        };
+        match self {
+            Self::None => vec![(x, y)],
+            Self::Horizontal => vec![(x, y), (mx, y)],
+            Self::Vertical => vec![(x, y), (x, my)],
+            Self::FourWay => vec![(x, y), (mx, y), (x, my), (mx, my)],
+        }
+    }
+}
+
+/// Drawn by dragging from one corner of its bounding box to the other
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Shape {
+    Line,
+    Rectangle { filled: bool },
+    Ellipse { filled: bool },
+}
+impl Shape {
+    /// The pixels of the shape, without duplicates
+    pub fn pixels(self, from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+        match self {
+            Self::Line => line(from, to),
+            Self::Rectangle { filled } => {
+                let (x0, x1) = (from.0.min(to.0), from.0.max(to.0));
+                let (y0, y1) = (from.1.min(to.1), from.1.max(to.1));
+                (y0..=y1)
+                    .flat_map(|y| (x0..=x1).map(move |x| (x, y)))
+                    .filter(|&(x, y)| filled || x == x0 || x == x1 || y == y0 || y == y1)
+                    .collect()
+            }
+            Self::Ellipse { filled: false } => ellipse(from, to),
+            Self::Ellipse { filled: true } => {
+                let outline = ellipse(from, to);
+                let (y0, y1) = (from.1.min(to.1), from.1.max(to.1));
+                (y0..=y1)
+                    .flat_map(|y| {
+                        let row = outline.iter().filter(|p| p.1 == y).map(|p| p.0);
+                        let (min, max) = (row.clone().min(), row.max());
+                        min.zip(max)
+                            .into_iter()
+                            .flat_map(move |(x0, x1)| (x0..=x1).map(move |x| (x, y)))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Outline of the ellipse that fits the box between two corners, using the midpoint algorithm
+pub fn ellipse(from: (usize, usize), to: (usize, usize)) -> Vec<(usize, usize)> {
+    // The tips below are drawn beside the box, which leaves out the ends of a flat one
+    if from.0 == to.0 || from.1 == to.1 {
+        return line(from, to);
+    }
+    let (mut x0, mut y0) = (from.0.min(to.0) as i64, from.1.min(to.1) as i64);
+    let (mut x1, mut y1) = (from.0.max(to.0) as i64, from.1.max(to.1) as i64);
+    let (left, right, top, bottom) = (x0, x1, y0, y1);
+    let mut pixels = vec![];
+    let mut plot = |x: i64, y: i64| {
+        let p = (x as usize, y as usize);
+        if (left..=right).contains(&x) && (top..=bottom).contains(&y) && !pixels.contains(&p) {
+            pixels.push(p);
+        }
+    };
+    let a = x1 - x0;
+    let b = y1 - y0;
+    let mut b1 = b & 1;
+    let mut dx = 4 * (1 - a) * b * b;
+    let mut dy = 4 * (b1 + 1) * a * a;
+    let mut err = dx + dy + b1 * a * a;
+    y0 += (b + 1) / 2;
+    y1 = y0 - b1;
+    let a8 = 8 * a * a;
+    b1 = 8 * b * b;
+    loop {
+        plot(x1, y0);
+        plot(x0, y0);
+        plot(x0, y1);
+        plot(x1, y1);
+        let e2 = 2 * err;
+        if e2 <= dy {
+            y0 += 1;
+            y1 -= 1;
+            dy += a8;
+            err += dy;
+        }
+        if e2 >= dx || 2 * err > dy {
+            x0 += 1;
+            x1 -= 1;
+            dx += b1;
+            err += dx;
+        }
+        if x0 > x1 {
+            break;
+        }
+    }
+    // Finish the tips of very flat ellipses
+    while y0 - y1 <= b {
+        plot(x0 - 1, y0);
+        plot(x1 + 1, y0);
+        y0 += 1;
+        plot(x0 - 1, y1);
+        plot(x1 + 1, y1);
+        y1 -= 1;
+    }
+    pixels
+}
+
+pub const MIN_CANVAS_SIZE: usize = 4;
+pub const MAX_CANVAS_SIZE: usize = 64;
+
+#[derive(Debug, Display, Clone, Copy, Default, PartialEq, EnumIter, Serialize, Deserialize)]
+pub enum WordPack {
+    #[default]
+    Fruits,
+    Animals,
+}
+impl WordPack {
+    pub fn words(&self, language: Language) -> &'static [&'static str] {
+        match (self, language) {
+            (Self::Fruits, Language::English) => FRUITS,
+            (Self::Fruits, Language::Swedish) => FRUITS_SV,
+            (Self::Animals, Language::English) => ANIMALS,
+            (Self::Animals, Language::Swedish) => ANIMALS_SV,
+        }
+    }
+}
+
+#[derive(Debug, Display, Clone, Copy, Default, PartialEq, EnumIter, Serialize, Deserialize)]
+pub enum Language {
+    #[default]
+    English,
+    Swedish,
+}
+
+/// Chosen by the host when creating the lobby, and editable until the game starts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomSettings {
+    pub canvas_width: usize,
+    pub canvas_height: usize,
+    pub palette: Palette,
+    /// Seconds each drawer has to get their word guessed
+    pub round_time: Option<u32>,
+    /// How many times each player gets to draw before the game ends
+    pub rounds: Option<u32>,
+    pub max_players: Option<usize>,
+    /// Seconds into a turn at which another letter of the prompt is revealed
+    pub hint_schedule: Vec<u32>,
+    /// Seconds a drawer may go without drawing before their turn is skipped
+    pub afk_timeout: Option<u32>,
+    pub word_pack: WordPack,
+    pub language: Language,
+    pub public: bool,
+}
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            canvas_width: 12,
+            canvas_height: 12,
+            palette: Palette::default(),
+            round_time: None,
+            rounds: None,
+            max_players: None,
+            hint_schedule: vec![],
+            afk_timeout: None,
+            word_pack: WordPack::default(),
+            language: Language::default(),
+            public: false,
+        }
+    }
+}
+impl RoomSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        let size = MIN_CANVAS_SIZE..=MAX_CANVAS_SIZE;
+        if !size.contains(&self.canvas_width) || !size.contains(&self.canvas_height) {
+            return Err(format!(
+                "canvas must be between {MIN_CANVAS_SIZE} and {MAX_CANVAS_SIZE} pixels wide and high"
+            ));
+        }
+        self.palette.validate()?;
+        if [self.round_time, self.rounds, self.afk_timeout].contains(&Some(0))
+            || self.max_players == Some(0)
+        {
+            return Err("limits must be greater than zero".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum GamePhase {
+    #[default]
+    Lobby,
+    Playing,
+    Finished,
+}
+
+/// What happened when the turn timer advanced
+#[derive(Debug, PartialEq)]
+pub enum Tick {
+    Continue,
+    Hint,
+    /// The drawer ran out of time, contains the word that nobody guessed
+    TurnOver(String),
+    /// The drawer didn't draw anything in time
+    Skipped {
+        username: String,
+        word: String,
+        /// Whether they were skipped too many times and got removed
+        removed: bool,
+    },
+    /// The last player was removed for being away, nobody is left to play
+    Abandoned {
+        username: String,
+    },
+}
+
+/// Skipped turns in a row before an idle player is removed from the game
+pub const MAX_SKIPPED_TURNS: u32 = 2;
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct GameState {
+    pub settings: RoomSettings,
+    pub phase: GamePhase,
+    pub host: String,
     pub prompt: String,
+    /// Indices of prompt characters revealed as hints
+    pub revealed: Vec<usize>,
     pub canvas: DrawCanvas,
     pub players: Vec<Player>,
+    pub spectators: Vec<Spectator>,
+    pub round: u32,
+    /// Seconds since the current drawer got their word
+    pub turn_elapsed: u32,
+    /// Whether the current drawer has drawn anything yet
+    pub drawn: bool,
+    /// Players that were kicked and may not come back
+    #[serde(default)]
+    pub banned: Vec<String>,
 }
 impl GameState {
-    pub fn new() -> Self {
+    pub fn new(settings: RoomSettings, host: String) -> Self {
         Self {
-            prompt: Self::random_prompt(None),
-            canvas: DrawCanvas::default(),
+            canvas: DrawCanvas::new(settings.canvas_width, settings.canvas_height),
+            settings,
+            phase: GamePhase::Lobby,
+            host,
+            prompt: String::new(),
+            revealed: vec![],
             players: vec![],
+            spectators: vec![],
+            round: 0,
+            turn_elapsed: 0,
+            drawn: false,
+            banned: vec![],
         }
     }
-    fn random_prompt(not: Option<&str>) -> String {
+    fn random_prompt(&self) -> String {
+        let words = self.settings.word_pack.words(self.settings.language);
         let mut rng: StdRng = SeedableRng::from_entropy();
         loop {
-            let p = FRUITS[rng.gen_range(0..FRUITS.len())].to_lowercase();
-            match not {
-                Some(prev) => {
-                    if p != prev {
-                        return p;
-                    }
-                }
-                None => return p,
+            let p = words[rng.gen_range(0..words.len())].to_lowercase();
+            if p != self.prompt || words.len() == 1 {
+                return p;
             }
         }
     }
+    /// The prompt as shown to guessers
+    pub fn masked_prompt(&self) -> String {
+        self.prompt
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if c.is_alphabetic() && !self.revealed.contains(&i) {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+    pub fn time_left(&self) -> Option<u32> {
+        match self.phase {
+            GamePhase::Playing => self
+                .settings
+                .round_time
+                .map(|t| t.saturating_sub(self.turn_elapsed)),
+            _ => None,
+        }
+    }
+    pub fn is_full(&self) -> bool {
+        self.settings
+            .max_players
+            .is_some_and(|max| self.players.len() >= max)
+    }
+    pub fn is_drawing(&self, username: &str) -> bool {
+        self.players
+            .iter()
+            .any(|p| p.active && p.username == username)
+    }
+    /// Whether the name is taken by a player or spectator
+    pub fn has_member(&self, username: &str) -> bool {
+        self.players.iter().any(|p| p.username == username) || self.is_spectator(username)
+    }
+    pub fn is_spectator(&self, username: &str) -> bool {
+        self.spectators.iter().any(|s| s.username == username)
+    }
+    /// Spectators only watch, and once the game is on only the drawer may change the canvas
+    pub fn may_draw(&self, username: &str) -> bool {
+        !self.is_spectator(username)
+            && !self.is_banned(username)
+            && (self.phase != GamePhase::Playing || self.is_drawing(username))
+    }
+    /// The canvas with its layers for players who may draw on it, flattened for everyone else
+    pub fn canvas_for(&self, username: Option<&str>) -> CanvasView<'_> {
+        let canvas = &self.canvas;
+        CanvasView {
+            width: canvas.width,
+            height: canvas.height,
+            grid: &canvas.grid,
+            layers: if username.is_some_and(|u| self.may_draw(u)) {
+                &canvas.layers
+            } else {
+                &[]
+            },
+        }
+    }
+    pub fn is_banned(&self, username: &str) -> bool {
+        self.banned.iter().any(|b| b == username)
+    }
+    /// Adds the player as a spectator if they asked to watch or the room is full.
     /// Returns whether player was added
-    pub fn add_player(&mut self, mut player: Player) -> bool {
-        if self.players.contains(&player) {
+    pub fn add_player(&mut self, mut player: Player, spectate: bool) -> bool {
+        if self.players.contains(&player) || self.is_spectator(&player.username) {
             return false;
         }
-        player.active = self.players.is_empty();
+        if spectate || self.is_full() {
+            self.spectators.push(Spectator {
+                username: player.username,
+                queued: !spectate,
+            });
+            return true;
+        }
+        player.active = false;
         self.players.push(player);
         true
     }
+    /// Queues a spectator for the next free slot, or lets them in right away.
+    /// Returns whether they became a player
+    pub fn request_play(&mut self, username: &str) -> bool {
+        let Some(s) = self.spectators.iter_mut().find(|s| s.username == username) else {
+            return false;
+        };
+        s.queued = true;
+        !self.promote_spectators().is_empty()
+    }
+    /// Moves queued spectators into free player slots, returns who got in
+    pub fn promote_spectators(&mut self) -> Vec<String> {
+        let mut promoted = vec![];
+        while !self.is_full() {
+            let Some(i) = self.spectators.iter().position(|s| s.queued) else {
+                break;
+            };
+            let s = self.spectators.remove(i);
+            self.players.push(Player::new(s.username.clone()));
+            promoted.push(s.username);
+        }
+        promoted
+    }
     /// Returns whether game should move to next round
     pub fn remove_player(&mut self, player: Player) -> bool {
-        self.players
-            .iter()
-            .find(|p| **p == player)
-            .map(|p| p.active)
-            .unwrap_or_default()
+        self.spectators.retain(|s| s.username != player.username);
+        let Some(i) = self.players.iter().position(|p| *p == player) else {
+            return false;
+        };
+        let removed = self.players.remove(i);
+        self.promote_spectators();
+        if self.host == removed.username {
+            self.host = self
+                .players
+                .first()
+                .map(|p| p.username.clone())
+                .unwrap_or_default();
+        }
+        if !removed.active || self.players.is_empty() {
+            return false;
+        }
+        // Hand the turn to whoever came after the leaving drawer. With nobody active
+        // `new_round` starts from the first player without counting a new round
+        if i > 0 {
+            self.players[i - 1].active = true;
+        }
+        true
+    }
+    /// Removes a player and keeps them from joining again.
+    /// Returns whether game should move to next round
+    pub fn kick(&mut self, username: &str) -> bool {
+        if !self.is_banned(username) {
+            self.banned.push(username.into());
+        }
+        self.remove_player(Player::new(username.into()))
+    }
+    /// Applies new settings, only allowed before the game has started
+    pub fn update_settings(&mut self, settings: RoomSettings) -> Result<(), String> {
+        if self.phase == GamePhase::Playing {
+            return Err("the game has already started".into());
+        }
+        settings.validate()?;
+        self.canvas = DrawCanvas::new(settings.canvas_width, settings.canvas_height);
+        self.settings = settings;
+        self.promote_spectators();
+        Ok(())
+    }
+    pub fn start_game(&mut self) {
+        self.phase = GamePhase::Playing;
+        self.round = 1;
+        self.canvas = DrawCanvas::new(self.settings.canvas_width, self.settings.canvas_height);
+        self.turn_elapsed = 0;
+        self.drawn = false;
+        self.revealed.clear();
+        for p in self.players.iter_mut() {
+            p.active = false;
+        }
+        if let Some(p) = self.players.first_mut() {
+            p.active = true;
+        }
+        self.prompt = self.random_prompt();
     }
     pub fn new_round(&mut self) {
-        self.canvas.clear();
-        self.prompt = Self::random_prompt(Some(&self.prompt));
+        self.canvas.reset();
+        self.turn_elapsed = 0;
+        self.drawn = false;
+        self.revealed.clear();
+        if self.players.is_empty() {
+            return;
+        }
         let i = match self.players.iter_mut().position(|p| {
             let b = p.active;
             p.active = false;
             b
         }) {
-            Some(j) => (j + 1) % self.players.len(),
+            Some(j) => j + 1,
             None => 0,
         };
+        let i = if i >= self.players.len() {
+            self.round += 1;
+            0
+        } else {
+            i
+        };
+        if self.settings.rounds.is_some_and(|r| self.round > r) {
+            self.phase = GamePhase::Finished;
+            self.prompt.clear();
+            return;
+        }
+        self.prompt = self.random_prompt();
         self.players[i].active = true;
     }
+    /// Advances the turn timer by one second
+    pub fn tick(&mut self) -> Tick {
+        if self.phase != GamePhase::Playing {
+            return Tick::Continue;
+        }
+        self.turn_elapsed += 1;
+        if !self.drawn
+            && self
+                .settings
+                .afk_timeout
+                .is_some_and(|t| self.turn_elapsed >= t)
+        {
+            return self.skip_idle_drawer();
+        }
+        if self
+            .settings
+            .round_time
+            .is_some_and(|t| self.turn_elapsed >= t)
+        {
+            let word = self.prompt.clone();
+            self.new_round();
+            return Tick::TurnOver(word);
+        }
+        if self.settings.hint_schedule.contains(&self.turn_elapsed) && self.reveal_hint() {
+            return Tick::Hint;
+        }
+        Tick::Continue
+    }
+    /// Marks that the drawer is still around, any drawing by others doesn't count
+    pub fn record_drawing(&mut self, username: &str) {
+        if let Some(p) = self
+            .players
+            .iter_mut()
+            .find(|p| p.active && p.username == username)
+        {
+            p.skipped_turns = 0;
+            self.drawn = true;
+        }
+    }
+    fn skip_idle_drawer(&mut self) -> Tick {
+        let word = self.prompt.clone();
+        let Some(drawer) = self.players.iter_mut().find(|p| p.active) else {
+            self.new_round();
+            return Tick::TurnOver(word);
+        };
+        drawer.skipped_turns += 1;
+        let username = drawer.username.clone();
+        let removed = drawer.skipped_turns >= MAX_SKIPPED_TURNS;
+        let advance = !removed || self.remove_player(Player::new(username.clone()));
+        if self.players.is_empty() {
+            // Otherwise the turn would time out over and over with nobody to draw
+            self.phase = GamePhase::Lobby;
+            self.prompt.clear();
+            return Tick::Abandoned { username };
+        }
+        if advance {
+            self.new_round();
+        }
+        Tick::Skipped {
+            username,
+            word,
+            removed,
+        }
+    }
+    /// Reveals a random hidden letter, but never the last one
+    fn reveal_hint(&mut self) -> bool {
+        let hidden: Vec<usize> = self
+            .prompt
+            .chars()
+            .enumerate()
+            .filter(|(i, c)| c.is_alphabetic() && !self.revealed.contains(i))
+            .map(|(i, _)| i)
+            .collect();
+        if hidden.len() <= 1 {
+            return false;
+        }
+        let mut rng: StdRng = SeedableRng::from_entropy();
+        self.revealed.push(hidden[rng.gen_range(0..hidden.len())]);
+        true
+    }
 }
 impl Default for GameState {
     fn default() -> Self {
-        Self::new()
+        Self::new(RoomSettings::default(), String::new())
     }
 }
 
@@ -118,18 +1268,89 @@ pub struct GameInfo {
     pub room_id: String,
     pub prompt: String,
     pub players: Vec<Player>,
+    pub spectators: Vec<Spectator>,
+    pub host: String,
+    pub phase: GamePhase,
+    pub round: u32,
+    pub time_left: Option<u32>,
+    pub settings: RoomSettings,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A public room as shown in the lobby browser
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomListing {
+    pub room_id: String,
+    pub players: usize,
+    pub phase: GamePhase,
+    pub settings: RoomSettings,
+    /// Whether a password is needed to join
+    pub locked: bool,
+}
+
+/// A game that played all its rounds, kept in the history
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct FinishedGame {
+    pub room_id: String,
+    pub players: Vec<String>,
+    pub rounds: u32,
+    /// Unix timestamp in seconds
+    pub finished_at: u64,
+}
+
+/// Letters that are hard to mix up when read out loud or handwritten
+const CODE_CONSONANTS: &[u8] = b"BDFGHJKMNPRSTVZ";
+const CODE_VOWELS: &[u8] = b"AEOU";
+
+/// Long enough for 216000 codes, so busy servers rarely have to retry
+pub const ROOM_CODE_LENGTH: usize = 6;
+
+/// A pronounceable room code of alternating consonants and vowels, like "BAKOTU"
+pub fn random_room_code(len: usize) -> String {
+    let mut rng: StdRng = SeedableRng::from_entropy();
+    (0..len)
+        .map(|i| {
+            let letters = if i % 2 == 0 {
+                CODE_CONSONANTS
+            } else {
+                CODE_VOWELS
+            };
+            letters[rng.gen_range(0..letters.len())] as char
+        })
+        .collect()
+}
+
+/// Accepts codes typed in lowercase or with separators, like "ba-ko"
+pub fn normalize_room_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionPlayer {
     pub username: String,
-    pub room: u32,
+    pub room: String,
+    #[serde(default)]
+    pub spectator: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Player {
     pub username: String,
     pub active: bool,
+    /// Turns in a row skipped for not drawing
+    #[serde(default)]
+    pub skipped_turns: u32,
+}
+impl Player {
+    pub fn new(username: String) -> Self {
+        Self {
+            username,
+            active: false,
+            skipped_turns: 0,
+        }
+    }
 }
 impl PartialEq for Player {
     fn eq(&self, other: &Self) -> bool {
@@ -137,15 +1358,79 @@ impl PartialEq for Player {
     }
 }
 
+/// Watches the canvas and chat, but can't draw or guess
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Spectator {
+    pub username: String,
+    /// Waiting for a free player slot
+    pub queued: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SetPixelPost {
-    pub pixel_id: usize,
+    pub x: usize,
+    pub y: usize,
     pub color: Color,
+    #[serde(default)]
+    pub layer: usize,
+}
+
+/// Every pixel painted in one drag, applied and broadcast at once
+#[derive(Serialize, Deserialize)]
+pub struct StrokePost {
+    /// Points the pointer passed, the brush and symmetry are applied on top
+    pub pixels: Vec<(usize, usize)>,
+    pub color: Color,
+    #[serde(default)]
+    pub brush: Brush,
+    #[serde(default)]
+    pub symmetry: Symmetry,
+    #[serde(default)]
+    pub layer: usize,
+    /// Makes the pixels transparent instead of painting them with `color`
+    #[serde(default)]
+    pub erase: bool,
+}
+
+/// Flood fills from a pixel
+#[derive(Serialize, Deserialize)]
+pub struct FillPost {
+    pub x: usize,
+    pub y: usize,
+    pub color: Color,
+    #[serde(default)]
+    pub layer: usize,
+}
+
+/// Changes to the stack of layers, which are numbered from the bottom
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum LayerPost {
+    Add,
+    Remove { layer: usize },
+    Move { from: usize, to: usize },
+    SetVisible { layer: usize, visible: bool },
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct JoinLobbyPost {
     pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Join as a spectator even if there is room to play
+    #[serde(default)]
+    pub spectate: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateLobbyPost {
+    pub username: String,
+    /// The server's defaults are used when left out
+    #[serde(default)]
+    pub settings: Option<RoomSettings>,
+    /// Players will need this to join the room
+    #[serde(default)]
+    pub password: Option<String>,
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
@@ -210,3 +1495,844 @@ pub const FRUITS: &[&str] = &[
     "Turnip",
     "Watermelon",
 ];
+
+pub const FRUITS_SV: &[&str] = &[
+    "Äpple",
+    "Aprikos",
+    "Kronärtskocka",
+    "Avokado",
+    "Banan",
+    "Rödbeta",
+    "Paprika",
+    "Björnbär",
+    "Blåbär",
+    "Broccoli",
+    "Brysselkål",
+    "Kål",
+    "Morot",
+    "Blomkål",
+    "Körsbär",
+    "Majs",
+    "Gurka",
+    "Aubergine",
+    "Fänkål",
+    "Vitlök",
+    "Grapefrukt",
+    "Vindruvor",
+    "Honungsmelon",
+    "Grönkål",
+    "Kiwi",
+    "Purjolök",
+    "Citron",
+    "Sallad",
+    "Mango",
+    "Mandarin",
+    "Nektarin",
+    "Lök",
+    "Apelsin",
+    "Papaya",
+    "Palsternacka",
+    "Persika",
+    "Päron",
+    "Ärtor",
+    "Ananas",
+    "Plommon",
+    "Granatäpple",
+    "Potatis",
+    "Pumpa",
+    "Russin",
+    "Rädisa",
+    "Hallon",
+    "Rabarber",
+    "Spenat",
+    "Squash",
+    "Jordgubbe",
+    "Sötpotatis",
+    "Tomat",
+    "Kålrot",
+    "Vattenmelon",
+];
+
+pub const ANIMALS: &[&str] = &[
+    "Bear",
+    "Bee",
+    "Butterfly",
+    "Camel",
+    "Cat",
+    "Chicken",
+    "Cow",
+    "Crab",
+    "Crocodile",
+    "Deer",
+    "Dog",
+    "Dolphin",
+    "Duck",
+    "Eagle",
+    "Elephant",
+    "Fish",
+    "Fox",
+    "Frog",
+    "Giraffe",
+    "Goat",
+    "Hedgehog",
+    "Horse",
+    "Kangaroo",
+    "Lion",
+    "Monkey",
+    "Moose",
+    "Mouse",
+    "Octopus",
+    "Owl",
+    "Penguin",
+    "Pig",
+    "Rabbit",
+    "Shark",
+    "Sheep",
+    "Snail",
+    "Snake",
+    "Spider",
+    "Squirrel",
+    "Tiger",
+    "Turtle",
+    "Whale",
+    "Wolf",
+    "Zebra",
+];
+
+pub const ANIMALS_SV: &[&str] = &[
+    "Björn",
+    "Bi",
+    "Fjäril",
+    "Kamel",
+    "Katt",
+    "Höna",
+    "Ko",
+    "Krabba",
+    "Krokodil",
+    "Hjort",
+    "Hund",
+    "Delfin",
+    "Anka",
+    "Örn",
+    "Elefant",
+    "Fisk",
+    "Räv",
+    "Groda",
+    "Giraff",
+    "Get",
+    "Igelkott",
+    "Häst",
+    "Känguru",
+    "Lejon",
+    "Apa",
+    "Älg",
+    "Mus",
+    "Bläckfisk",
+    "Uggla",
+    "Pingvin",
+    "Gris",
+    "Kanin",
+    "Haj",
+    "Får",
+    "Snigel",
+    "Orm",
+    "Spindel",
+    "Ekorre",
+    "Tiger",
+    "Sköldpadda",
+    "Val",
+    "Varg",
+    "Zebra",
+];
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    fn game(names: &[&str], rounds: Option<u32>) -> GameState {
+        let settings = RoomSettings {
+            rounds,
+            ..RoomSettings::default()
+        };
+        let mut gs = GameState::new(settings, names[0].into());
+        for name in names {
+            gs.add_player(Player::new(name.to_string()), false);
+        }
+        gs.start_game();
+        gs
+    }
+    fn drawer(gs: &GameState) -> &str {
+        &gs.players.iter().find(|p| p.active).unwrap().username
+    }
+
+    #[test]
+    fn first_drawer_leaving_keeps_the_round() {
+        let mut gs = game(&["a", "b", "c"], Some(1));
+        assert!(gs.remove_player(Player::new("a".into())));
+        gs.new_round();
+        assert_eq!(gs.round, 1);
+        assert_eq!(gs.phase, GamePhase::Playing);
+        assert_eq!(drawer(&gs), "b");
+    }
+
+    #[test]
+    fn middle_drawer_leaving_hands_over_the_turn() {
+        let mut gs = game(&["a", "b", "c"], None);
+        gs.new_round();
+        assert_eq!(drawer(&gs), "b");
+        assert!(gs.remove_player(Player::new("b".into())));
+        gs.new_round();
+        assert_eq!((gs.round, drawer(&gs)), (1, "c"));
+    }
+
+    #[test]
+    fn last_drawer_leaving_ends_the_round() {
+        let mut gs = game(&["a", "b", "c"], None);
+        gs.new_round();
+        gs.new_round();
+        assert_eq!(drawer(&gs), "c");
+        assert!(gs.remove_player(Player::new("c".into())));
+        gs.new_round();
+        assert_eq!((gs.round, drawer(&gs)), (2, "a"));
+    }
+
+    #[test]
+    fn guesser_leaving_does_not_advance() {
+        let mut gs = game(&["a", "b"], None);
+        assert!(!gs.remove_player(Player::new("b".into())));
+        assert_eq!(drawer(&gs), "a");
+    }
+
+    #[test]
+    fn only_the_drawer_draws_during_a_game() {
+        let mut gs = GameState::new(RoomSettings::default(), "a".into());
+        gs.add_player(Player::new("a".into()), false);
+        gs.add_player(Player::new("b".into()), false);
+        gs.add_player(Player::new("s".into()), true);
+        assert!(gs.may_draw("a") && gs.may_draw("b"));
+        assert!(!gs.may_draw("s"));
+        gs.start_game();
+        assert!(gs.may_draw("a"));
+        assert!(!gs.may_draw("b") && !gs.may_draw("s"));
+        assert!(gs.canvas_for(Some("b")).layers.is_empty());
+        assert!(!gs.canvas_for(Some("a")).layers.is_empty());
+    }
+
+    #[test]
+    fn members_are_players_and_spectators() {
+        let mut gs = game(&["a"], None);
+        gs.add_player(Player::new("s".into()), true);
+        assert!(gs.has_member("a") && gs.has_member("s"));
+        assert!(!gs.has_member("x"));
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        assert!(RoomSettings::default().validate().is_ok());
+        let invalid = [
+            RoomSettings {
+                canvas_width: MIN_CANVAS_SIZE - 1,
+                ..RoomSettings::default()
+            },
+            RoomSettings {
+                canvas_height: MAX_CANVAS_SIZE + 1,
+                ..RoomSettings::default()
+            },
+            RoomSettings {
+                rounds: Some(0),
+                ..RoomSettings::default()
+            },
+            RoomSettings {
+                max_players: Some(0),
+                ..RoomSettings::default()
+            },
+        ];
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{settings:?}");
+        }
+    }
+
+    #[test]
+    fn settings_are_locked_once_playing() {
+        let mut gs = GameState::new(RoomSettings::default(), "a".into());
+        gs.add_player(Player::new("a".into()), false);
+        let settings = RoomSettings {
+            canvas_width: 20,
+            ..RoomSettings::default()
+        };
+        assert!(gs.update_settings(settings.clone()).is_ok());
+        assert_eq!(gs.canvas.width, 20);
+        gs.start_game();
+        assert!(gs.update_settings(RoomSettings::default()).is_err());
+        assert_eq!(gs.settings, settings);
+    }
+
+    #[test]
+    fn full_rooms_turn_joiners_into_spectators() {
+        let settings = RoomSettings {
+            max_players: Some(1),
+            ..RoomSettings::default()
+        };
+        let mut gs = GameState::new(settings, "a".into());
+        assert!(gs.add_player(Player::new("a".into()), false));
+        assert!(gs.add_player(Player::new("b".into()), false));
+        assert_eq!(gs.players.len(), 1);
+        assert!(gs.is_spectator("b"));
+        // Asked to play, so they get the slot as soon as it frees up
+        assert!(!gs.request_play("b"));
+        gs.add_player(Player::new("c".into()), true);
+        assert!(!gs.remove_player(Player::new("a".into())));
+        assert_eq!(gs.players[0].username, "b");
+        assert!(gs.is_spectator("c"));
+    }
+
+    #[test]
+    fn spectators_join_when_the_limit_goes_up() {
+        let settings = RoomSettings {
+            max_players: Some(1),
+            ..RoomSettings::default()
+        };
+        let mut gs = GameState::new(settings.clone(), "a".into());
+        gs.add_player(Player::new("a".into()), false);
+        gs.add_player(Player::new("b".into()), false);
+        gs.add_player(Player::new("c".into()), true);
+        gs.update_settings(RoomSettings {
+            max_players: None,
+            ..settings
+        })
+        .unwrap();
+        assert_eq!(gs.players.len(), 2);
+        // Only the one that wanted to play was moved over
+        assert!(gs.is_spectator("c") && !gs.may_draw("c"));
+    }
+
+    #[test]
+    fn hints_never_reveal_the_whole_word() {
+        let settings = RoomSettings {
+            hint_schedule: vec![1, 2, 3],
+            ..RoomSettings::default()
+        };
+        let mut gs = GameState::new(settings, "a".into());
+        gs.add_player(Player::new("a".into()), false);
+        gs.start_game();
+        gs.prompt = "ab c".into();
+        assert_eq!(gs.masked_prompt(), "__ _");
+        assert_eq!(gs.tick(), Tick::Hint);
+        assert_eq!(gs.tick(), Tick::Hint);
+        assert_eq!(gs.tick(), Tick::Continue);
+        assert_eq!(gs.masked_prompt().matches('_').count(), 1);
+    }
+
+    #[test]
+    fn game_finishes_after_the_last_round() {
+        let mut gs = game(&["a", "b"], Some(1));
+        gs.new_round();
+        assert_eq!(gs.phase, GamePhase::Playing);
+        gs.new_round();
+        assert_eq!(gs.phase, GamePhase::Finished);
+        assert!(gs.prompt.is_empty());
+    }
+
+    #[test]
+    fn room_codes_survive_sloppy_typing() {
+        let code = random_room_code(ROOM_CODE_LENGTH);
+        assert_eq!(code.len(), ROOM_CODE_LENGTH);
+        assert_eq!(normalize_room_code(&code.to_lowercase()), code);
+        assert_eq!(normalize_room_code(" ba-ko tu "), "BAKOTU");
+    }
+
+    fn afk_game(names: &[&str]) -> GameState {
+        let mut gs = game(names, None);
+        gs.settings.afk_timeout = Some(2);
+        gs
+    }
+
+    #[test]
+    fn idle_drawers_are_skipped_then_removed() {
+        let mut gs = afk_game(&["a", "b", "c"]);
+        assert_eq!(gs.tick(), Tick::Continue);
+        assert!(matches!(gs.tick(), Tick::Skipped { removed: false, .. }));
+        assert_eq!(drawer(&gs), "b");
+        gs.record_drawing("b");
+        assert_eq!((gs.tick(), gs.tick()), (Tick::Continue, Tick::Continue));
+        gs.new_round();
+        gs.new_round();
+        assert_eq!(drawer(&gs), "a");
+        gs.tick();
+        let tick = gs.tick();
+        assert!(matches!(tick, Tick::Skipped { removed: true, username, .. } if username == "a"));
+        assert_eq!(gs.players.len(), 2);
+        assert_eq!(gs.phase, GamePhase::Playing);
+    }
+
+    #[test]
+    fn only_the_drawer_counts_as_drawing() {
+        let mut gs = afk_game(&["a", "b"]);
+        gs.record_drawing("b");
+        gs.tick();
+        assert!(matches!(gs.tick(), Tick::Skipped { .. }));
+    }
+
+    #[test]
+    fn last_player_going_away_abandons_the_game() {
+        let mut gs = afk_game(&["a"]);
+        gs.players[0].skipped_turns = MAX_SKIPPED_TURNS - 1;
+        gs.tick();
+        assert_eq!(
+            gs.tick(),
+            Tick::Abandoned {
+                username: "a".into()
+            }
+        );
+        assert!(gs.players.is_empty());
+        assert_eq!(gs.phase, GamePhase::Lobby);
+        assert_eq!(gs.tick(), Tick::Continue);
+    }
+
+    const INK: Color = Color(1);
+    const RED: Color = Color(2);
+
+    /// The canvas as rows of palette indices
+    fn rows(canvas: &DrawCanvas) -> Vec<Vec<u8>> {
+        canvas
+            .grid
+            .chunks(canvas.width)
+            .map(|row| row.iter().map(|c| c.0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn pixels_are_addressed_by_x_then_y() {
+        let mut canvas = DrawCanvas::new(3, 2);
+        assert!(canvas.set_pixel(0, 2, 1, INK));
+        assert_eq!(rows(&canvas), [[0, 0, 0], [0, 0, 1]]);
+        assert_eq!(canvas.get_pixel(2, 1), Some(INK));
+        // Out of bounds on either axis, rather than wrapping onto the next row
+        assert_eq!(canvas.index(3, 0), None);
+        assert_eq!(canvas.index(0, 2), None);
+        assert!(!canvas.set_pixel(0, 3, 0, INK));
+        assert_eq!(canvas.get_pixel(0, 2), None);
+    }
+
+    #[test]
+    fn canvas_follows_the_room_size() {
+        let settings = RoomSettings {
+            canvas_width: 20,
+            canvas_height: 8,
+            ..RoomSettings::default()
+        };
+        let gs = GameState::new(settings, "a".into());
+        assert_eq!((gs.canvas.width, gs.canvas.height), (20, 8));
+        assert_eq!(gs.canvas.grid.len(), 160);
+    }
+
+    #[test]
+    fn paint_is_all_or_nothing() {
+        let mut canvas = DrawCanvas::new(4, 4);
+        assert!(!canvas.paint(0, &[(0, 0), (4, 0)], INK));
+        assert!(canvas.grid.iter().all(|&c| c == Color::default()));
+        assert!(canvas.paint(0, &[(0, 0), (3, 3)], INK));
+        assert_eq!(
+            (canvas.get_pixel(0, 0), canvas.get_pixel(3, 3)),
+            (Some(INK), Some(INK))
+        );
+    }
+
+    #[test]
+    fn lines_include_both_ends() {
+        assert_eq!(line((0, 0), (0, 0)), [(0, 0)]);
+        assert_eq!(line((0, 0), (3, 0)), [(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(line((2, 2), (0, 0)), [(2, 2), (1, 1), (0, 0)]);
+        let steep = line((0, 0), (1, 4));
+        assert_eq!(steep.len(), 5);
+        assert_eq!((steep[0], steep[4]), ((0, 0), (1, 4)));
+        // Every step moves to a neighbouring pixel
+        for w in steep.windows(2) {
+            assert!(w[0].0.abs_diff(w[1].0) <= 1 && w[1].1 == w[0].1 + 1);
+        }
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut canvas = DrawCanvas::new(3, 1);
+        canvas.set_pixel(0, 0, 0, INK);
+        canvas.set_pixel(0, 1, 0, RED);
+        canvas.clear();
+        assert_eq!(rows(&canvas), [[0, 0, 0]]);
+        assert!(canvas.undo());
+        assert_eq!(rows(&canvas), [[1, 2, 0]]);
+        assert!(canvas.undo());
+        assert_eq!(rows(&canvas), [[1, 0, 0]]);
+        assert!(canvas.redo());
+        assert_eq!(rows(&canvas), [[1, 2, 0]]);
+        // A new edit drops what could have been redone
+        canvas.set_pixel(0, 2, 0, INK);
+        assert!(!canvas.redo());
+        assert_eq!(rows(&canvas), [[1, 2, 1]]);
+    }
+
+    #[test]
+    fn unchanged_pixels_are_not_an_edit() {
+        let mut canvas = DrawCanvas::new(2, 1);
+        canvas.set_pixel(0, 0, 0, INK);
+        canvas.set_pixel(0, 0, 0, INK);
+        // Neither is a stroke that didn't go through
+        assert!(!canvas.paint(0, &[(1, 0), (2, 0)], INK));
+        assert!(canvas.undo());
+        assert!(!canvas.undo());
+    }
+
+    #[test]
+    fn undo_history_is_capped() {
+        let mut canvas = DrawCanvas::new(MAX_UNDO + 5, 1);
+        for x in 0..canvas.width {
+            canvas.set_pixel(0, x, 0, INK);
+        }
+        let mut undone = 0;
+        while canvas.undo() {
+            undone += 1;
+        }
+        assert_eq!(undone, MAX_UNDO);
+        assert_eq!(canvas.get_pixel(4, 0), Some(INK));
+        assert_eq!(canvas.get_pixel(5, 0), Some(Color::default()));
+    }
+
+    #[test]
+    fn reset_forgets_history() {
+        let mut canvas = DrawCanvas::new(2, 2);
+        canvas.set_pixel(0, 0, 0, INK);
+        canvas.reset();
+        assert!(!canvas.undo());
+        assert_eq!(rows(&canvas), [[0, 0], [0, 0]]);
+    }
+
+    #[test]
+    fn canvas_view_reads_back_as_a_canvas() {
+        let mut gs = GameState::new(RoomSettings::default(), "a".into());
+        gs.add_player(Player::new("a".into()), false);
+        gs.canvas.set_pixel(0, 1, 1, INK);
+        let sent = |username| {
+            let json = serde_json::to_string(&gs.canvas_for(username)).unwrap();
+            serde_json::from_str::<DrawCanvas>(&json).unwrap()
+        };
+        assert!(
+            sent(Some("a"))
+                == DrawCanvas {
+                    undo: vec![],
+                    changes: None,
+                    ..gs.canvas.clone()
+                }
+        );
+        assert!(sent(None) == gs.canvas.flattened());
+    }
+
+    #[test]
+    fn deltas_patch_the_canvas_a_client_has() {
+        let mut canvas = DrawCanvas::new(4, 4);
+        let mut drawer = canvas.clone();
+        let mut guesser = canvas.flattened();
+        let mut send = |canvas: &mut DrawCanvas| {
+            let delta = canvas.take_delta().unwrap();
+            drawer.apply(&delta);
+            let json = serde_json::to_string(&CanvasUpdate::<CanvasView>::Pixels(delta)).unwrap();
+            let CanvasUpdate::<DrawCanvas>::Pixels(delta) = serde_json::from_str(&json).unwrap()
+            else {
+                panic!("{json}");
+            };
+            guesser.apply(&delta);
+            assert!(drawer.grid == canvas.grid && drawer.layers == canvas.layers);
+            assert!(guesser.grid == canvas.grid);
+        };
+        assert!(canvas.paint(0, &[(0, 0), (1, 0)], INK));
+        assert_eq!(
+            canvas.clone().take_delta().unwrap().grid,
+            [(0, INK), (1, INK)]
+        );
+        send(&mut canvas);
+        assert_eq!(canvas.take_delta(), Some(CanvasDelta::default()));
+        assert!(canvas.undo());
+        send(&mut canvas);
+        assert!(canvas.redo());
+        send(&mut canvas);
+        // Changes to the layers, or to much of the canvas, resend all of it
+        assert!(canvas.add_layer());
+        assert_eq!(canvas.take_delta(), None);
+        assert!(canvas.paint(1, &[(1, 0)], RED));
+        assert_eq!(canvas.fill(0, 3, 3, RED), Some(14));
+        assert_eq!(canvas.take_delta(), None);
+        assert!(canvas.paint(1, &[(2, 2)], INK));
+        assert!(canvas.take_delta().is_some());
+    }
+
+    #[test]
+    fn fill_stays_inside_the_outline() {
+        let mut canvas = DrawCanvas::new(5, 5);
+        let mut outline: Vec<_> = (0..4)
+            .flat_map(|i| [(i, 0), (i, 3), (0, i), (3, i)])
+            .collect();
+        outline.sort();
+        outline.dedup();
+        canvas.paint(0, &outline, INK);
+        assert_eq!(canvas.fill(0, 1, 1, RED), Some(4));
+        assert_eq!(
+            rows(&canvas),
+            [
+                [1, 1, 1, 1, 0],
+                [1, 2, 2, 1, 0],
+                [1, 2, 2, 1, 0],
+                [1, 1, 1, 1, 0],
+                [0, 0, 0, 0, 0],
+            ]
+        );
+        // The outside is 4-connected around the corner, but not through it
+        assert_eq!(canvas.fill(0, 4, 4, RED), Some(9));
+        assert_eq!(canvas.get_pixel(1, 1), Some(RED));
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_pixel(4, 4), Some(Color::default()));
+        assert_eq!(canvas.fill(0, 5, 0, RED), None);
+        // The whole fill is one edit
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_pixel(1, 1), Some(Color::default()));
+    }
+
+    #[test]
+    fn fill_on_another_layer_stays_inside_the_outline() {
+        let mut canvas = DrawCanvas::new(5, 5);
+        let outline = Shape::Rectangle { filled: false }.pixels((0, 0), (3, 3));
+        canvas.paint(0, &outline, INK);
+        canvas.add_layer();
+        assert_eq!(canvas.fill(1, 1, 1, RED), Some(4));
+        assert_eq!(canvas.layers[1].pixels.iter().flatten().count(), 4);
+        assert_eq!(
+            (canvas.layers[0].pixels[6], canvas.layers[1].pixels[6]),
+            (None, Some(RED))
+        );
+        assert_eq!(canvas.get_pixel(1, 1), Some(RED));
+        assert_eq!(canvas.fill(2, 1, 1, RED), None);
+    }
+
+    #[test]
+    fn background_color_covers_and_eraser_uncovers() {
+        let mut canvas = DrawCanvas::new(2, 1);
+        canvas.paint(0, &[(0, 0), (1, 0)], INK);
+        canvas.add_layer();
+        canvas.paint(1, &[(0, 0), (1, 0)], Color::default());
+        assert_eq!(rows(&canvas), [[0, 0]]);
+        canvas.erase(1, &[(1, 0)]);
+        assert_eq!(rows(&canvas), [[0, 1]]);
+        assert!(canvas.undo());
+        assert_eq!(rows(&canvas), [[0, 0]]);
+    }
+
+    #[test]
+    fn layers_stack_hide_and_move() {
+        let mut canvas = DrawCanvas::new(1, 1);
+        canvas.set_pixel(0, 0, 0, INK);
+        assert!(canvas.add_layer());
+        canvas.set_pixel(1, 0, 0, RED);
+        assert_eq!(canvas.get_pixel(0, 0), Some(RED));
+        assert!(canvas.set_layer_visible(1, false));
+        assert_eq!(canvas.get_pixel(0, 0), Some(INK));
+        assert!(canvas.set_layer_visible(1, true));
+        assert!(canvas.move_layer(1, 0));
+        assert_eq!(canvas.get_pixel(0, 0), Some(INK));
+        assert!(canvas.undo());
+        assert_eq!(canvas.get_pixel(0, 0), Some(RED));
+        assert!(canvas.remove_layer(1));
+        assert!(!canvas.remove_layer(0));
+        assert_eq!(canvas.get_pixel(0, 0), Some(INK));
+        while canvas.add_layer() {}
+        assert_eq!(canvas.layers.len(), MAX_LAYERS);
+    }
+
+    #[test]
+    fn layer_changes_undo_and_redo() {
+        let mut canvas = DrawCanvas::new(1, 1);
+        canvas.set_pixel(0, 0, 0, INK);
+        assert!(canvas.add_layer());
+        canvas.set_pixel(1, 0, 0, RED);
+        let names = |canvas: &DrawCanvas| -> Vec<String> {
+            canvas.layers.iter().map(|l| l.name.clone()).collect()
+        };
+        let drawn = canvas.clone();
+        // Every change comes back with its undo, and goes again with its redo
+        let mut check = |change: fn(&mut DrawCanvas) -> bool| {
+            assert!(change(&mut canvas));
+            let changed = canvas.clone();
+            assert!(changed.layers != drawn.layers);
+            assert!(canvas.undo());
+            assert!(canvas.layers == drawn.layers && canvas.grid == drawn.grid);
+            assert!(canvas.redo());
+            assert!(canvas.layers == changed.layers && canvas.grid == changed.grid);
+            assert!(canvas.undo());
+        };
+        check(|c| c.set_layer_visible(1, false));
+        check(|c| c.move_layer(1, 0));
+        check(|c| c.remove_layer(0));
+        check(|c| c.add_layer());
+        assert_eq!(names(&canvas), ["Layer 1", "Layer 2"]);
+        assert!(canvas.remove_layer(0));
+        assert_eq!(names(&canvas), ["Layer 2"]);
+        assert_eq!(canvas.get_pixel(0, 0), Some(RED));
+        assert!(canvas.undo());
+        assert_eq!(canvas.layers[0].pixels, [Some(INK)]);
+        // Changes that leave the layers as they are aren't kept
+        assert!(canvas.set_layer_visible(0, true));
+        assert!(canvas.move_layer(1, 1));
+        assert!(canvas.undo());
+        assert_eq!(canvas.layers[1].pixels, [None]);
+    }
+
+    #[test]
+    fn flattened_canvases_get_a_layer_when_drawn_on() {
+        let mut canvas = DrawCanvas::new(2, 1);
+        canvas.set_pixel(0, 0, 0, INK);
+        let mut flat = canvas.flattened();
+        assert!(flat.layers.is_empty());
+        assert!(flat.set_pixel(0, 1, 0, RED));
+        assert_eq!(flat.layers[0].pixels, [Some(INK), Some(RED)]);
+    }
+
+    #[test]
+    fn rectangles_from_any_corner() {
+        let mut canvas = DrawCanvas::new(4, 3);
+        let outline = Shape::Rectangle { filled: false }.pixels((3, 2), (0, 0));
+        assert_eq!(outline.len(), 10);
+        canvas.paint(0, &outline, INK);
+        assert_eq!(rows(&canvas), [[1, 1, 1, 1], [1, 0, 0, 1], [1, 1, 1, 1]]);
+        let filled = Shape::Rectangle { filled: true }.pixels((0, 0), (3, 2));
+        assert_eq!(filled.len(), 12);
+        // Filled ellipses cover their outline
+        let ellipse = Shape::Ellipse { filled: true }.pixels((0, 0), (6, 4));
+        assert!(Shape::Ellipse { filled: false }
+            .pixels((0, 0), (6, 4))
+            .iter()
+            .all(|p| ellipse.contains(p)));
+    }
+
+    #[test]
+    fn flat_ellipses_are_lines() {
+        assert_eq!(ellipse((0, 0), (0, 5)), line((0, 0), (0, 5)));
+        assert_eq!(ellipse((5, 2), (1, 2)), line((5, 2), (1, 2)));
+        assert_eq!(ellipse((3, 3), (3, 3)), [(3, 3)]);
+        let filled = Shape::Ellipse { filled: true }.pixels((2, 0), (2, 3));
+        assert_eq!(filled.len(), 4);
+    }
+
+    #[test]
+    fn ellipses_touch_every_side_of_their_box() {
+        for w in 0..8 {
+            for h in 0..8 {
+                let pixels = ellipse((1, 1), (1 + w, 1 + h));
+                let xs = pixels.iter().map(|p| p.0);
+                let ys = pixels.iter().map(|p| p.1);
+                assert_eq!(
+                    (xs.clone().min(), xs.max()),
+                    (Some(1), Some(1 + w)),
+                    "{w}x{h}"
+                );
+                assert_eq!(
+                    (ys.clone().min(), ys.max()),
+                    (Some(1), Some(1 + h)),
+                    "{w}x{h}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stamp_applies_brush_and_symmetry() {
+        let canvas = DrawCanvas::new(4, 4);
+        let mut pixels = canvas
+            .stamp(&[(0, 0)], Brush::Square2, Symmetry::None)
+            .unwrap();
+        pixels.sort();
+        assert_eq!(pixels, [(0, 0), (0, 1), (1, 0), (1, 1)]);
+        let mut pixels = canvas
+            .stamp(&[(0, 1)], Brush::Square1, Symmetry::FourWay)
+            .unwrap();
+        pixels.sort();
+        assert_eq!(pixels, [(0, 1), (0, 2), (3, 1), (3, 2)]);
+        // Overlapping points are only listed once
+        let pixels = canvas
+            .stamp(&[(1, 1), (1, 2)], Brush::Square3, Symmetry::None)
+            .unwrap();
+        assert_eq!(pixels.len(), 12);
+        assert_eq!(
+            canvas.stamp(&[(4, 0)], Brush::Square1, Symmetry::None),
+            None
+        );
+    }
+
+    #[test]
+    fn stamp_clips_at_every_edge() {
+        let canvas = DrawCanvas::new(5, 4);
+        for brush in Brush::iter() {
+            for symmetry in Symmetry::iter() {
+                for (x, y) in (0..4).flat_map(|y| (0..5).map(move |x| (x, y))) {
+                    let pixels = canvas.stamp(&[(x, y)], brush, symmetry).unwrap();
+                    assert!(pixels.contains(&(x, y)), "{brush} {symmetry} at {x},{y}");
+                    for &p in &pixels {
+                        assert!(canvas.index(p.0, p.1).is_some());
+                        for m in symmetry.mirror(p, canvas.width, canvas.height) {
+                            assert!(pixels.contains(&m), "{brush} {symmetry} at {x},{y}");
+                        }
+                    }
+                }
+            }
+        }
+        assert!(Symmetry::None.mirror((5, 0), 5, 4).is_empty());
+    }
+
+    #[test]
+    fn palettes_are_validated() {
+        for palette in Palette::presets() {
+            assert_eq!(palette.validate(), Ok(()), "{}", palette.name);
+        }
+        let mut palette = Palette::default();
+        palette.colors.clear();
+        assert!(palette.validate().is_err());
+        palette.colors = vec![Palette::default().colors[0].clone(); MAX_PALETTE_SIZE];
+        assert_eq!(palette.validate(), Ok(()));
+        palette.colors.push(palette.colors[0].clone());
+        assert!(palette.validate().is_err());
+        palette.colors.truncate(2);
+        palette.colors[1].name = " ".into();
+        assert!(palette.validate().is_err());
+        palette.colors[1].name = "x".repeat(33);
+        assert!(palette.validate().is_err());
+        palette.colors[1].name = "Ink".into();
+        palette.name = String::new();
+        assert!(palette.validate().is_err());
+    }
+
+    #[test]
+    fn palette_colors_and_ink() {
+        assert_eq!(Rgb::hex(0x1d2b53), Rgb(0x1d, 0x2b, 0x53));
+        assert_eq!(Rgb::hex(0x00ff0a).css(), "#00ff0a");
+        let gameboy = &Palette::presets()[4];
+        assert_eq!(gameboy.name, "Game Boy");
+        assert_eq!(gameboy.ink(), Color(3));
+        assert_eq!(gameboy.css(Color(2)), "#306230");
+        // Unknown colors fall back to the background
+        assert_eq!(gameboy.rgb(Color(4)), Rgb::hex(0x9bbc0f));
+        assert_eq!(Palette::default().ink(), Color(2));
+        let empty = Palette {
+            name: "Empty".into(),
+            colors: Vec::new(),
+        };
+        assert_eq!(empty.ink(), Color(0));
+        assert_eq!(empty.rgb(Color(1)), Rgb(255, 255, 255));
+    }
+
+    #[test]
+    fn color_blind_palettes_are_offered() {
+        let names: Vec<_> = Palette::presets().into_iter().map(|p| p.name).collect();
+        assert!(names.iter().any(|n| n == "Color-blind safe"));
+        assert!(names.iter().any(|n| n == "Tol bright"));
+        assert!(Rgb::hex(0x000000).is_dark() && Rgb::hex(0x0072b2).is_dark());
+        assert!(!Rgb::hex(0xffffff).is_dark() && !Rgb::hex(0xf0e442).is_dark());
+    }
+}
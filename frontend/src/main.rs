@@ -1,4 +1,9 @@
-use components::{game::Game, navbar::LoginForm};
+use common::SessionPlayer;
+use components::{
+    browser::RoomBrowser,
+    game::Game,
+    navbar::{JoinCodeForm, LoginForm},
+};
 use gloo_net::http::Request;
 use stylist::{
     css,
@@ -16,6 +21,8 @@ fn main() {
 enum Route {
     #[at("/")]
     Home,
+    #[at("/rooms")]
+    Rooms,
     #[at("/game/:room_id")]
     Game { room_id: String },
     #[not_found]
@@ -33,19 +40,6 @@ fn app() -> Html {
         *, *::before, *::after {
             box-sizing: border-box;
         }
-
-        .white { background-color: white; }
-        .gray { background-color: gray; }
-        .black { background-color: black; }
-        .red { background-color: red; }
-        .orange { background-color: orange; }
-        .yellow { background-color: yellow; }
-        .lime { background-color: lime; }
-        .green { background-color: green; }
-        .blue { background-color: blue; }
-        .cyan { background-color: cyan; }
-        .magenta { background-color: magenta; }
-        .purple { background-color: purple; }
     "#
     );
     let wrapper_style = use_style!(
@@ -57,7 +51,7 @@ fn app() -> Html {
         margin: auto;
     "#
     );
-    let player = use_state(|| None as Option<String>);
+    let player = use_state(|| None as Option<SessionPlayer>);
     use_effect_with_deps(
         {
             let player = player.clone();
@@ -79,24 +73,31 @@ fn app() -> Html {
     html! {
         <>
             <Global css={glob_style}/>
-            <ContextProvider<Option<String>> context={(*player).clone()}>
+            <ContextProvider<Option<SessionPlayer>> context={(*player).clone()}>
                 <BrowserRouter>
                     <div class={wrapper_style}>
                         <Switch<Route> render={|r| match r {
-                            Route::Home => html! { <LoginForm create_lobby={true} /> },
+                            Route::Home => html! {
+                                <>
+                                    <LoginForm create_lobby={true} />
+                                    <JoinCodeForm />
+                                    <a href="/rooms" style="color: #eee;">{"Browse public rooms"}</a>
+                                </>
+                            },
+                            Route::Rooms => html! { <RoomBrowser /> },
                             Route::Game { room_id } => html! { <Game {room_id} /> },
                             Route::NotFound => html! { "Not found 🤔" },
                         }} />
                     </div>
                 </BrowserRouter>
-            </ContextProvider<Option<String>>>
+            </ContextProvider<Option<SessionPlayer>>>
         </>
     }
 }
 
 mod components {
     pub mod navbar {
-        use common::{GameInfo, JoinLobbyPost};
+        use common::{normalize_room_code, CreateLobbyPost, GameInfo, JoinLobbyPost};
         use gloo_net::http::Request;
         use stylist::yew::use_style;
         use wasm_bindgen::JsCast;
@@ -112,6 +113,20 @@ mod components {
             let player = use_context::<Option<String>>().unwrap();
             let game_info = use_context::<GameInfo>().unwrap();
             let room_id = game_info.room_id;
+            let copied = use_state(|| false);
+            let copy_invite = {
+                let copied = copied.clone();
+                let room_id = room_id.clone();
+                Callback::from(move |_| {
+                    let window = web_sys::window().unwrap();
+                    let origin = window.location().origin().unwrap();
+                    let _ = window
+                        .navigator()
+                        .clipboard()
+                        .write_text(&format!("{origin}/game/{room_id}"));
+                    copied.set(true);
+                })
+            };
             let style = use_style!(
                 r#"
                 display: flex;
@@ -121,25 +136,54 @@ mod components {
                 color: #eee;
                 padding: 10px;
                 border-radius: 10px;
-                & > a, & > form, & > div {
+                & > a, & > form, & > div, & > button {
                     background-color: #6e7eef5e;
                     padding: 10px;
                     border-radius: 10px;
+                    border: none;
                     color: #eee;
                     text-decoration: none;
                 }
+                & > button {
+                    cursor: pointer;
+                    font: inherit;
+                }
             "#
             );
             html! {
                 <div class={style}>
                     <a href="/">{ "Create Lobby" }</a>
+                    <a href="/rooms">{ "Browse Rooms" }</a>
+                    <div>{ format!("Room {room_id}") }</div>
+                    <button onclick={copy_invite}>
+                        { if *copied { "Invite link copied!" } else { "Copy invite link" } }
+                    </button>
                     <i style="flex-grow: 1;"></i>
                     {
                         if let Some(p) = player {
+                            let spectator = game_info.spectators.iter().find(|s| s.username == p);
                             html! {
                                 <>
-                                    <div>{&format!("Playing as \"{p}\"")}</div>
-                                    <a href="/api/leave_lobby">{"Leave game"}</a>
+                                    if let Some(s) = spectator {
+                                        <div>{&format!("Spectating as \"{p}\"")}</div>
+                                        if s.queued {
+                                            <div>{"Waiting for a free slot..."}</div>
+                                        } else {
+                                            <button onclick={Callback::from(|_| {
+                                                spawn_local(async move {
+                                                    Request::post("/api/play").send().await.unwrap();
+                                                });
+                                            })}>{"Join game"}</button>
+                                        }
+                                    } else {
+                                        <div>{&format!("Playing as \"{p}\"")}</div>
+                                    }
+                                    <button onclick={Callback::from(|_| {
+                                        spawn_local(async move {
+                                            Request::post("/api/leave_lobby").send().await.unwrap();
+                                            web_sys::window().unwrap().location().set_href("/").unwrap();
+                                        });
+                                    })}>{"Leave game"}</button>
                                 </>
                             }
                         } else {
@@ -149,18 +193,61 @@ mod components {
                 </div>
             }
         }
+        /// Takes players to the join form of a room they got the code for
+        #[function_component]
+        pub fn JoinCodeForm() -> Html {
+            let code = use_state(String::new);
+            let onchange = {
+                let code = code.clone();
+                Callback::from(move |e: Event| {
+                    code.set(
+                        e.target()
+                            .unwrap()
+                            .unchecked_into::<HtmlInputElement>()
+                            .value(),
+                    );
+                })
+            };
+            let onsubmit = {
+                let code = code.clone();
+                Callback::from(move |e: SubmitEvent| {
+                    e.prevent_default();
+                    let code = normalize_room_code(&code);
+                    if !code.is_empty() {
+                        web_sys::window()
+                            .unwrap()
+                            .location()
+                            .assign(&format!("/game/{code}"))
+                            .unwrap();
+                    }
+                })
+            };
+            html! {
+                <form {onsubmit}>
+                    <label for="icode">{"Room code: "}</label>
+                    <input id="icode" type="text" placeholder="BAKOTU" value={(*code).clone()} {onchange} />
+                    <input type="submit" value="Join" />
+                </form>
+            }
+        }
         #[derive(PartialEq, Properties)]
         pub struct LoginFormProps {
             pub create_lobby: bool,
             pub room_to_join: Option<String>,
+            #[prop_or_default]
+            pub quick_play: bool,
         }
         #[function_component]
         pub fn LoginForm(props: &LoginFormProps) -> Html {
             let LoginFormProps {
                 create_lobby,
                 room_to_join,
+                quick_play,
             } = props;
             let username = use_state(String::new);
+            let password = use_state(String::new);
+            let spectate = use_state(|| false);
+            let error = use_state(|| None as Option<&'static str>);
             let onchangeu = {
                 let username = username.clone();
                 Callback::from(move |e: Event| {
@@ -173,30 +260,76 @@ mod components {
                     );
                 })
             };
+            let onchangep = {
+                let password = password.clone();
+                Callback::from(move |e: Event| {
+                    password.set(
+                        e.target()
+                            .unwrap()
+                            .unchecked_into::<HtmlInputElement>()
+                            .value(),
+                    );
+                })
+            };
+            let onchanges = {
+                let spectate = spectate.clone();
+                Callback::from(move |e: Event| {
+                    spectate.set(
+                        e.target()
+                            .unwrap()
+                            .unchecked_into::<HtmlInputElement>()
+                            .checked(),
+                    );
+                })
+            };
             let onsubmit = {
                 let username = username.clone();
+                let password = password.clone();
+                let spectate = spectate.clone();
+                let error = error.clone();
                 let create_lobby = *create_lobby;
+                let quick_play = *quick_play;
                 let room_to_join = room_to_join.clone();
                 Callback::from(move |e: SubmitEvent| {
                     e.prevent_default();
                     let username = username.clone();
+                    let password = Some((*password).clone()).filter(|p| !p.is_empty());
+                    let spectate = *spectate;
+                    let error = error.clone();
                     let create_lobby = create_lobby;
                     let room_to_join = room_to_join.clone();
                     spawn_local(async move {
-                        let endp = if create_lobby {
+                        let endp = if quick_play {
+                            "/api/quick_play".to_string()
+                        } else if create_lobby {
                             "/api/create_lobby".to_string()
                         } else {
                             format!("/api/join_lobby/{}", room_to_join.unwrap())
                         };
-                        let resp = Request::post(&endp)
-                            .json(&JoinLobbyPost {
+                        let req = Request::post(&endp);
+                        let req = if create_lobby {
+                            req.json(&CreateLobbyPost {
                                 username: (*username).clone(),
+                                settings: None,
+                                password,
                             })
-                            .unwrap()
-                            .send()
-                            .await
-                            .unwrap();
-                        if create_lobby {
+                        } else {
+                            req.json(&JoinLobbyPost {
+                                username: (*username).clone(),
+                                password,
+                                spectate,
+                            })
+                        };
+                        let resp = req.unwrap().send().await.unwrap();
+                        match resp.status() {
+                            200 => error.set(None),
+                            401 => return error.set(Some("Wrong password")),
+                            404 => return error.set(Some("Room not found")),
+                            409 => return error.set(Some("That name is taken in this room")),
+                            429 => return error.set(Some("Too many attempts, try again later")),
+                            _ => return error.set(Some("Something went wrong")),
+                        }
+                        if create_lobby || quick_play {
                             let room = resp.text().await.unwrap();
                             web_sys::window()
                                 .unwrap()
@@ -213,20 +346,242 @@ mod components {
                 <form {onsubmit}>
                     <label for="ilogin">{"Username: "}</label>
                     <input id="ilogin" type="text" value={(*username).clone()} onchange={onchangeu} />
-                    // <input type="password" value={(*password).clone()} onchange={onchangep} />
+                    if !*quick_play {
+                        <input
+                            type="password"
+                            placeholder={if *create_lobby { "Password (optional)" } else { "Password" }}
+                            value={(*password).clone()}
+                            onchange={onchangep}
+                        />
+                    }
+                    if room_to_join.is_some() {
+                        <label>
+                            <input type="checkbox" checked={*spectate} onchange={onchanges} />
+                            {"Spectate"}
+                        </label>
+                    }
                     <input type="submit" value="Enter" />
+                    if let Some(error) = *error {
+                        <span>{" "}{error}</span>
+                    }
                 </form>
             }
         }
     }
-    pub mod game {
-        use super::{canvas::Canvas, chat::Chat, navbar::NavBar};
-        use common::GameInfo;
-        use futures::StreamExt;
-        use gloo_net::websocket::{futures::WebSocket, Message};
+    pub mod browser {
+        use super::navbar::LoginForm;
+        use common::{GamePhase, RoomListing, SessionPlayer};
+        use gloo_net::http::Request;
         use stylist::yew::use_style;
         use wasm_bindgen_futures::spawn_local;
+        use yew::prelude::*;
+
+        #[function_component(RoomBrowser)]
+        pub fn room_browser() -> Html {
+            // Only the room our session belongs to takes us straight back in
+            let session_room = use_context::<Option<SessionPlayer>>()
+                .unwrap()
+                .map(|p| p.room);
+            let rooms = use_state(Vec::<RoomListing>::new);
+            let refresh = {
+                let rooms = rooms.clone();
+                Callback::from(move |_| {
+                    let rooms = rooms.clone();
+                    spawn_local(async move {
+                        let r = Request::get("/api/rooms")
+                            .send()
+                            .await
+                            .unwrap()
+                            .json()
+                            .await
+                            .unwrap();
+                        rooms.set(r);
+                    });
+                })
+            };
+            use_effect_with_deps(
+                {
+                    let refresh = refresh.clone();
+                    move |_| refresh.emit(())
+                },
+                (),
+            );
+            let style = use_style!(
+                r#"
+                display: flex;
+                flex-direction: column;
+                gap: 10px;
+                background-color: #6e7eef5e;
+                color: #eee;
+                padding: 10px;
+                border-radius: 10px;
+
+                table {
+                    border-collapse: collapse;
+                }
+                th, td {
+                    padding: 5px;
+                    text-align: left;
+                }
+                tr:nth-child(even) {
+                    background-color: #00000022;
+                }
+                a {
+                    color: #eee;
+                }
+            "#
+            );
+            html! {
+                <div class={style}>
+                    <div>
+                        <b>{"Quick play "}</b>
+                        <LoginForm create_lobby={false} quick_play={true} />
+                    </div>
+                    <div>
+                        <b>{"Public rooms "}</b>
+                        <button onclick={refresh.reform(|_| ())}>{"Refresh"}</button>
+                    </div>
+                    <table>
+                        <tr>
+                            <th>{"Room"}</th>
+                            <th>{"Players"}</th>
+                            <th>{"Status"}</th>
+                            <th>{"Canvas"}</th>
+                            <th>{"Words"}</th>
+                            <th></th>
+                        </tr>
+                        {
+                            rooms.iter().map(|r| {
+                                let status = match r.phase {
+                                    GamePhase::Lobby => "In lobby",
+                                    GamePhase::Playing => "Playing",
+                                    GamePhase::Finished => "Finished",
+                                };
+                                html! {
+                                    <tr>
+                                        <td>{&r.room_id}{if r.locked { " 🔒" } else { "" }}</td>
+                                        <td>{
+                                            match r.settings.max_players {
+                                                Some(max) => format!("{}/{max}", r.players),
+                                                None => r.players.to_string(),
+                                            }
+                                        }</td>
+                                        <td>{status}</td>
+                                        <td>{format!("{}x{}", r.settings.canvas_width, r.settings.canvas_height)}</td>
+                                        <td>{format!("{} ({})", r.settings.word_pack, r.settings.language)}</td>
+                                        <td><a href={format!("/game/{}", r.room_id)}>{
+                                            if session_room.as_ref() == Some(&r.room_id) { "Rejoin" } else { "Join" }
+                                        }</a></td>
+                                    </tr>
+                                }
+                            }).collect::<Html>()
+                        }
+                    </table>
+                    {if rooms.is_empty() { html! { <i>{"No public rooms right now, why not create one?"}</i> } } else { html! {} }}
+                </div>
+            }
+        }
+    }
+    pub mod socket {
+        use futures::StreamExt;
+        use gloo_net::websocket::{futures::WebSocket, Message, WebSocketError};
+        use gloo_timers::future::sleep;
+        use std::time::Duration;
+        use wasm_bindgen_futures::spawn_local;
         use web_sys::console;
+        use yew::Callback;
+
+        /// Give up after this many reconnects in a row without hearing from the server
+        const MAX_RECONNECTS: u32 = 8;
+        /// Close codes the server uses when a room is closed or a player kicked,
+        /// reconnecting won't help then
+        const FINAL_CLOSE_CODES: [u16; 2] = [1000, 1008];
+
+        /// Socket traffic is only logged to the console after running
+        /// `localStorage.setItem("pixel-skribbl-debug", "1")`, it is too noisy otherwise
+        fn debug_enabled() -> bool {
+            web_sys::window()
+                .and_then(|w| w.local_storage().ok().flatten())
+                .and_then(|s| s.get_item("pixel-skribbl-debug").ok().flatten())
+                .is_some_and(|v| v == "1")
+        }
+
+        /// Streams text messages from one of the room's WebSockets into `on_message`.
+        /// Reconnects with backoff when the connection drops, e.g. during a server restart.
+        /// A socket that never got through, like one to a room that is gone or one we haven't
+        /// given the password for, is not retried: it wouldn't get through later either
+        pub fn subscribe(room_id: String, stream: &'static str, on_message: Callback<String>) {
+            let location = web_sys::window().unwrap().location();
+            let host = location.host().unwrap();
+            let secure = location.protocol().unwrap() == "https:";
+            let url = format!(
+                "ws{}://{host}/ws/{room_id}/{stream}",
+                if secure { "s" } else { "" }
+            );
+            spawn_local(async move {
+                let debug = debug_enabled();
+                let mut attempts = 0;
+                let mut connected = false;
+                loop {
+                    if let Ok(ws) = WebSocket::open(&url) {
+                        let (mut _write, mut read) = ws.split();
+                        while let Some(msg) = read.next().await {
+                            match msg {
+                                Ok(Message::Text(msg)) => {
+                                    if debug {
+                                        console::log_1(
+                                            &format!("Received on {stream} {:?}", msg).into(),
+                                        );
+                                    }
+                                    attempts = 0;
+                                    connected = true;
+                                    on_message.emit(msg);
+                                }
+                                Ok(Message::Bytes(_)) => {}
+                                Err(WebSocketError::ConnectionClose(e))
+                                    if FINAL_CLOSE_CODES.contains(&e.code) =>
+                                {
+                                    if debug {
+                                        console::log_1(
+                                            &format!("{stream} WebSocket closed: {}", e.reason)
+                                                .into(),
+                                        );
+                                    }
+                                    return;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                    }
+                    if debug {
+                        console::log_1(&format!("{stream} WebSocket Closed").into());
+                    }
+                    if !connected || attempts == MAX_RECONNECTS {
+                        return;
+                    }
+                    attempts += 1;
+                    sleep(Duration::from_millis(500 << attempts.min(5))).await;
+                }
+            });
+        }
+    }
+    pub mod throttle {
+        use gloo_timers::callback::Timeout;
+        use yew::UseStateHandle;
+
+        /// Shows a "slow down" notice for a moment when the server rate limited a request
+        pub fn notice(status: u16, slow_down: &UseStateHandle<bool>) {
+            if status == 429 {
+                slow_down.set(true);
+                let slow_down = slow_down.clone();
+                Timeout::new(2_000, move || slow_down.set(false)).forget();
+            }
+        }
+    }
+    pub mod game {
+        use super::{canvas::Canvas, chat::Chat, lobby::Lobby, navbar::NavBar, socket::subscribe};
+        use common::{normalize_room_code, GameInfo, GamePhase, SessionPlayer};
+        use stylist::yew::use_style;
         use yew::prelude::*;
 
         #[derive(PartialEq, Properties)]
@@ -237,6 +592,12 @@ mod components {
         #[function_component]
         pub fn Game(props: &GameProps) -> Html {
             let GameProps { room_id } = props;
+            // A session for another room doesn't make us a player here, the join form is
+            // shown instead and nothing we draw would reach this room
+            let player = use_context::<Option<SessionPlayer>>()
+                .unwrap()
+                .filter(|p| p.room == normalize_room_code(room_id))
+                .map(|p| p.username);
             let gi = use_state(|| GameInfo {
                 room_id: room_id.clone(),
                 ..Default::default()
@@ -246,23 +607,14 @@ mod components {
                     let gi = gi.clone();
                     let room_id = room_id.clone();
                     move |_| {
-                        let host = web_sys::window().unwrap().location().host().unwrap();
-                        let secure =
-                            web_sys::window().unwrap().location().protocol().unwrap() == "https:";
-                        let ws = WebSocket::open(&format!(
-                            "ws{}://{host}/ws/{room_id}/game",
-                            if secure { "s" } else { "" }
-                        ))
-                        .unwrap();
-                        let (mut _write, mut read) = ws.split();
-                        spawn_local(async move {
-                            while let Some(Ok(Message::Text(msg))) = read.next().await {
-                                console::log_1(&format!("Received on game {:?}", msg).into());
+                        subscribe(
+                            room_id,
+                            "game",
+                            Callback::from(move |msg: String| {
                                 let g: GameInfo = serde_json::from_str(&msg).unwrap();
                                 gi.set(g);
-                            }
-                            console::log_1(&"Game WebSocket Closed".into());
-                        });
+                            }),
+                        );
                     }
                 },
                 (),
@@ -280,69 +632,273 @@ mod components {
             );
             html! {
                 <div class={style}>
-                    <ContextProvider<GameInfo> context={(*gi).clone()}>
-                        <NavBar />
-                        <Canvas />
-                        <Chat />
-                    </ContextProvider<GameInfo>>
+                    <ContextProvider<Option<String>> context={player}>
+                        <ContextProvider<GameInfo> context={(*gi).clone()}>
+                            <NavBar />
+                            {if gi.phase == GamePhase::Playing { html! {} } else { html! { <Lobby /> } }}
+                            <Canvas />
+                            <Chat />
+                        </ContextProvider<GameInfo>>
+                    </ContextProvider<Option<String>>>
                 </div>
             }
         }
     }
     pub mod canvas {
-        use super::pixel::Pixel;
-        use common::{Color, DrawCanvas, GameInfo, SetPixelPost};
-        use futures::StreamExt;
-        use gloo_net::{
-            http::Request,
-            websocket::{futures::WebSocket, Message},
+        use super::{
+            pixel::{self, glyph, patterns_enabled, set_patterns_enabled},
+            socket::subscribe,
+            throttle,
+        };
+        use common::{
+            line, Brush, CanvasUpdate, Color, DrawCanvas, FillPost, GameInfo, GamePhase, LayerPost,
+            Shape, StrokePost, Symmetry, MAX_LAYERS,
         };
+        use gloo_events::EventListener;
+        use gloo_net::http::Request;
         use strum::IntoEnumIterator;
         use stylist::yew::use_style;
+        use wasm_bindgen::JsCast;
         use wasm_bindgen_futures::spawn_local;
-        use web_sys::console;
+        use web_sys::{Element, HtmlCanvasElement, HtmlInputElement};
         use yew::prelude::*;
 
+        /// Widest the canvas is shown at without zooming, in CSS pixels
+        const VIEW_SIZE: f64 = 480.0;
+        /// Keeps the backing store of small canvases at high zoom reasonable
+        const MAX_BACKING_SIZE: u32 = 4096;
+        const ZOOM_LEVELS: [u32; 4] = [1, 2, 4, 8];
+
+        #[derive(Clone, Copy, PartialEq)]
+        enum Tool {
+            Brush,
+            /// Makes pixels on the layer transparent again
+            Eraser,
+            Fill,
+            Line,
+            Rectangle,
+            Ellipse,
+            Pan,
+        }
+        impl Tool {
+            const ALL: [(Tool, &'static str); 7] = [
+                (Tool::Brush, "Brush"),
+                (Tool::Eraser, "Eraser"),
+                (Tool::Fill, "Fill"),
+                (Tool::Line, "Line"),
+                (Tool::Rectangle, "Rect"),
+                (Tool::Ellipse, "Ellipse"),
+                (Tool::Pan, "Pan"),
+            ];
+            fn shape(self, filled: bool) -> Option<Shape> {
+                match self {
+                    Tool::Brush | Tool::Eraser | Tool::Fill | Tool::Pan => None,
+                    Tool::Line => Some(Shape::Line),
+                    Tool::Rectangle => Some(Shape::Rectangle { filled }),
+                    Tool::Ellipse => Some(Shape::Ellipse { filled }),
+                }
+            }
+        }
+
+        /// The drag in progress, sent when the pointer is released
+        struct Stroke {
+            layer: usize,
+            color: Color,
+            erase: bool,
+            brush: Brush,
+            symmetry: Symmetry,
+            /// Drawn from `start` to where the pointer is, instead of freehand
+            shape: Option<Shape>,
+            start: (usize, usize),
+            last: (usize, usize),
+            pixels: Vec<(usize, usize)>,
+        }
+        impl Stroke {
+            fn extend_to(&mut self, to: (usize, usize)) {
+                match self.shape {
+                    Some(shape) => self.pixels = shape.pixels(self.start, to),
+                    None => {
+                        for p in line(self.last, to) {
+                            if !self.pixels.contains(&p) {
+                                self.pixels.push(p);
+                            }
+                        }
+                    }
+                }
+                self.last = to;
+            }
+            /// Paints the stroke the same way the server will
+            fn paint_on(&self, canvas: &mut DrawCanvas) {
+                if let Some(pixels) = canvas.stamp(&self.pixels, self.brush, self.symmetry) {
+                    if self.erase {
+                        canvas.erase(self.layer, &pixels);
+                    } else {
+                        canvas.paint(self.layer, &pixels, self.color);
+                    }
+                }
+            }
+        }
+
+        /// The option after `current`, wrapping around
+        fn next<T: IntoEnumIterator + PartialEq>(current: T) -> T {
+            let mut all = T::iter().cycle();
+            all.find(|t| *t == current);
+            all.next().unwrap()
+        }
+
+        /// Posts a canvas action that has no body
+        fn send_action(path: &'static str, slow_down: UseStateHandle<bool>) {
+            spawn_local(async move {
+                let resp = Request::post(path).send().await.unwrap();
+                throttle::notice(resp.status(), &slow_down);
+            });
+        }
+
+        fn send_layers(change: LayerPost, slow_down: UseStateHandle<bool>) {
+            spawn_local(async move {
+                let resp = Request::post("/api/layers")
+                    .json(&change)
+                    .unwrap()
+                    .send()
+                    .await
+                    .unwrap();
+                throttle::notice(resp.status(), &slow_down);
+            });
+        }
+
+        /// Where a pan started: pointer position and how far the view was scrolled
+        struct Pan {
+            pointer: (i32, i32),
+            scroll: (i32, i32),
+        }
+
+        /// Backing pixels per grid pixel. A whole number keeps edges crisp, and it grows with
+        /// the zoom and the screen's pixel ratio so zoomed in pixels don't get blurry
+        fn cell_size(canvas: &DrawCanvas, zoom: u32) -> u32 {
+            let ratio = web_sys::window().map_or(1.0, |w| w.device_pixel_ratio());
+            let side = canvas.width.max(canvas.height).max(1) as u32;
+            let cell = (VIEW_SIZE * ratio * zoom as f64 / side as f64).ceil() as u32;
+            cell.clamp(1, (MAX_BACKING_SIZE / side).max(1))
+        }
+
+        /// The pixel under the pointer, if it is over the canvas
+        fn pixel_at(e: &PointerEvent, el: &Element, canvas: &DrawCanvas) -> Option<(usize, usize)> {
+            let rect = el.get_bounding_client_rect();
+            let fx = (e.client_x() as f64 - rect.left()) / rect.width();
+            let fy = (e.client_y() as f64 - rect.top()) / rect.height();
+            if !(0.0..1.0).contains(&fx) || !(0.0..1.0).contains(&fy) {
+                return None;
+            }
+            Some((
+                (fx * canvas.width as f64) as usize,
+                (fy * canvas.height as f64) as usize,
+            ))
+        }
+
         #[function_component(Canvas)]
         pub fn canvas() -> Html {
-            let width = use_state_eq(|| 12usize);
-            let height = use_state_eq(|| 12usize);
-            let dc = DrawCanvas::default();
-            let grid = use_state_eq(|| dc.grid);
-
             let game_info = use_context::<GameInfo>().unwrap();
+            // Sized from the settings until the first canvas arrives
+            let canvas = {
+                let settings = &game_info.settings;
+                use_state_eq(|| DrawCanvas::new(settings.canvas_width, settings.canvas_height))
+            };
+            // The canvas as the server has it, patched with the pixels it sends. `canvas` may
+            // also show strokes the server hasn't sent back yet
+            let server_canvas = use_mut_ref(|| (*canvas).clone());
             let room_id = game_info.room_id;
             let prompt = game_info.prompt;
+            let palette = game_info.settings.palette;
+            let player = use_context::<Option<String>>().unwrap();
+            // Same as the server: spectators only watch, and once the game is on only the
+            // drawer may touch the canvas
+            let may_draw = player.is_some_and(|p| {
+                !game_info.spectators.iter().any(|s| s.username == p)
+                    && (game_info.phase != GamePhase::Playing
+                        || game_info
+                            .players
+                            .iter()
+                            .any(|d| d.active && d.username == p))
+            });
 
-            let selected_color = use_state(|| Color::Black);
+            let selected_color = use_state(|| palette.ink());
+            // The palette may have changed since the color was picked
+            let color = if palette.get(*selected_color).is_some() {
+                *selected_color
+            } else {
+                palette.ink()
+            };
+            let selected_layer = use_state_eq(|| 0usize);
+            // Layers may have been removed since it was picked
+            let layer = (*selected_layer).min(canvas.layers.len().saturating_sub(1));
+            let tool = use_state_eq(|| Tool::Brush);
+            let filled = use_state_eq(|| false);
+            let brush = use_state_eq(Brush::default);
+            let symmetry = use_state_eq(Symmetry::default);
+            let patterns = use_state_eq(patterns_enabled);
+            let slow_down = use_state_eq(|| false);
+            let zoom = use_state_eq(|| 1u32);
+            let stroke = use_mut_ref(|| None::<Stroke>);
+            let pan = use_mut_ref(|| None::<Pan>);
+            let drawn = use_mut_ref(|| None::<pixel::Drawn>);
+            let grid_ref = use_node_ref();
+            let view_ref = use_node_ref();
+            let redraw = use_force_update();
 
             use_effect_with_deps(
                 {
-                    let grid = grid.clone();
+                    let canvas = canvas.clone();
+                    let server_canvas = server_canvas.clone();
                     let room_id = room_id.clone();
                     move |_| {
-                        let host = web_sys::window().unwrap().location().host().unwrap();
-                        let secure =
-                            web_sys::window().unwrap().location().protocol().unwrap() == "https:";
-                        let ws = WebSocket::open(&format!(
-                            "ws{}://{host}/ws/{room_id}/canvas",
-                            if secure { "s" } else { "" }
-                        ))
-                        .unwrap();
-                        let (mut _write, mut read) = ws.split();
-                        spawn_local(async move {
-                            while let Some(Ok(Message::Text(msg))) = read.next().await {
-                                console::log_1(&format!("Received on canvas {:?}", msg).into());
-                                let c: DrawCanvas = serde_json::from_str(&msg).unwrap();
-                                grid.set(c.grid);
-                            }
-                            console::log_1(&"Canvas WebSocket Closed".into());
-                        });
+                        subscribe(
+                            room_id,
+                            "canvas",
+                            Callback::from(move |msg: String| {
+                                let mut server_canvas = server_canvas.borrow_mut();
+                                match serde_json::from_str(&msg).unwrap() {
+                                    CanvasUpdate::Full(full) => *server_canvas = full,
+                                    CanvasUpdate::Pixels(delta) => server_canvas.apply(&delta),
+                                }
+                                canvas.set(server_canvas.clone());
+                            }),
+                        );
                     }
                 },
                 (),
             );
 
+            // Undo and redo shortcuts, unless typing in the chat
+            use_effect_with_deps(
+                {
+                    let slow_down = slow_down.clone();
+                    move |&may_draw: &bool| {
+                        let listener = may_draw.then(|| {
+                            let window = web_sys::window().unwrap();
+                            EventListener::new(&window, "keydown", move |e| {
+                                let e = e.dyn_ref::<KeyboardEvent>().unwrap();
+                                let typing = e
+                                    .target()
+                                    .is_some_and(|t| t.dyn_ref::<HtmlInputElement>().is_some());
+                                if !(e.ctrl_key() || e.meta_key()) || typing {
+                                    return;
+                                }
+                                let path = match e.key().to_lowercase().as_str() {
+                                    "z" if e.shift_key() => "/api/redo",
+                                    "z" => "/api/undo",
+                                    "y" => "/api/redo",
+                                    _ => return,
+                                };
+                                e.prevent_default();
+                                send_action(path, slow_down.clone());
+                            })
+                        });
+                        move || drop(listener)
+                    }
+                },
+                may_draw,
+            );
+
             let style = use_style!(
                 r#"
                 display: flex;
@@ -360,18 +916,56 @@ mod components {
                 letter-spacing: .2em;
             "#
             );
+            let status_style = use_style!(
+                r#"
+                color: #eee;
+            "#
+            );
+            let view_style = use_style!(
+                r#"
+                width: 100%;
+                max-width: ${size}px;
+                aspect-ratio: ${width} / ${height};
+                overflow: auto;
+            "#,
+                size = VIEW_SIZE,
+                width = canvas.width,
+                height = canvas.height,
+            );
             let canvas_style = use_style!(
                 r#"
-                display: grid;
-                grid-template-columns: repeat(${width}, 1fr);
-                grid-template-rows: repeat(${height}, 1fr);
+                display: block;
+                width: ${zoom}%;
+                image-rendering: pixelated;
                 user-select: none;
-                width: 100%;
-                max-width: 480px;
-                aspect-ratio: 1;
+                touch-action: none;
+                cursor: ${cursor};
             "#,
-                width = *width,
-                height = *height,
+                zoom = *zoom * 100,
+                cursor = if *tool == Tool::Pan || !may_draw {
+                    "grab"
+                } else {
+                    "crosshair"
+                },
+            );
+            let view_controls_style = use_style!(
+                r#"
+                display: flex;
+                gap: 5px;
+                align-items: center;
+                color: #eee;
+            "#
+            );
+            let patterns_style = use_style!(
+                r#"
+                background-color: #6e7eef5e;
+                color: #eee;
+                border: none;
+                border-radius: 10px;
+                padding: 5px 10px;
+                cursor: pointer;
+                font: inherit;
+            "#
             );
             let controls_style = use_style!(
                 r#"
@@ -392,12 +986,224 @@ mod components {
                     user-select: none;
                     cursor: pointer;
                 }
+                > .selectColor.tool {
+                    background-color: white;
+                }
                 > .selectColor.selected {
                     border: 2px dashed black;
                     box-shadow: inset 0 0 9px 5px #ffffff80;
                 }
             "#
             );
+            let layers_style = use_style!(
+                r#"
+                display: flex;
+                flex-direction: column;
+                gap: 5px;
+                background-color: #6e7eef5e;
+                padding: 10px;
+                border-radius: 10px;
+                color: #eee;
+
+                > .layer {
+                    display: flex;
+                    gap: 5px;
+                    align-items: center;
+                }
+                > .layer > .name {
+                    flex: 1;
+                    cursor: pointer;
+                }
+                > .layer.selected > .name {
+                    font-weight: bold;
+                }
+                > .layer.hidden > .name {
+                    opacity: .5;
+                }
+            "#
+            );
+            let onpointerdown = {
+                let stroke = stroke.clone();
+                let grid_ref = grid_ref.clone();
+                let canvas = canvas.clone();
+                let tool = tool.clone();
+                let filled = filled.clone();
+                let brush = brush.clone();
+                let symmetry = symmetry.clone();
+                let slow_down = slow_down.clone();
+                let redraw = redraw.clone();
+                let pan = pan.clone();
+                let view_ref = view_ref.clone();
+                Callback::from(move |e: PointerEvent| {
+                    let (Some(el), Some(view)) =
+                        (grid_ref.cast::<Element>(), view_ref.cast::<Element>())
+                    else {
+                        return;
+                    };
+                    // The middle button pans with any tool, guessers and spectators can only pan
+                    if *tool == Tool::Pan || !may_draw || e.button() == 1 {
+                        // No autoscroll on middle click
+                        e.prevent_default();
+                        let _ = el.set_pointer_capture(e.pointer_id());
+                        *pan.borrow_mut() = Some(Pan {
+                            pointer: (e.client_x(), e.client_y()),
+                            scroll: (view.scroll_left(), view.scroll_top()),
+                        });
+                        return;
+                    }
+                    if e.button() != 0 {
+                        return;
+                    }
+                    let Some(p) = pixel_at(&e, &el, &canvas) else {
+                        return;
+                    };
+                    if *tool == Tool::Fill {
+                        let mut filled = (*canvas).clone();
+                        filled.fill(layer, p.0, p.1, color);
+                        canvas.set(filled);
+                        let slow_down = slow_down.clone();
+                        spawn_local(async move {
+                            let resp = Request::post("/api/fill")
+                                .json(&FillPost {
+                                    x: p.0,
+                                    y: p.1,
+                                    color,
+                                    layer,
+                                })
+                                .unwrap()
+                                .send()
+                                .await
+                                .unwrap();
+                            throttle::notice(resp.status(), &slow_down);
+                        });
+                    } else {
+                        // Keep getting moves when the pointer leaves the canvas mid-stroke
+                        let _ = el.set_pointer_capture(e.pointer_id());
+                        *stroke.borrow_mut() = Some(Stroke {
+                            layer,
+                            color,
+                            erase: *tool == Tool::Eraser,
+                            brush: *brush,
+                            symmetry: *symmetry,
+                            shape: tool.shape(*filled),
+                            start: p,
+                            last: p,
+                            pixels: vec![p],
+                        });
+                        redraw.force_update();
+                    }
+                })
+            };
+            let onpointermove = {
+                let stroke = stroke.clone();
+                let grid_ref = grid_ref.clone();
+                let canvas = canvas.clone();
+                let redraw = redraw.clone();
+                let pan = pan.clone();
+                let view_ref = view_ref.clone();
+                Callback::from(move |e: PointerEvent| {
+                    if let (Some(p), Some(view)) = (&*pan.borrow(), view_ref.cast::<Element>()) {
+                        view.set_scroll_left(p.scroll.0 - (e.client_x() - p.pointer.0));
+                        view.set_scroll_top(p.scroll.1 - (e.client_y() - p.pointer.1));
+                        return;
+                    }
+                    let mut stroke = stroke.borrow_mut();
+                    let (Some(s), Some(el)) = (stroke.as_mut(), grid_ref.cast::<Element>()) else {
+                        return;
+                    };
+                    if let Some(p) = pixel_at(&e, &el, &canvas).filter(|&p| p != s.last) {
+                        s.extend_to(p);
+                        redraw.force_update();
+                    }
+                })
+            };
+            let finish_stroke = {
+                let stroke = stroke.clone();
+                let canvas = canvas.clone();
+                let slow_down = slow_down.clone();
+                let pan = pan.clone();
+                Callback::from(move |_: PointerEvent| {
+                    pan.borrow_mut().take();
+                    let Some(s) = stroke.borrow_mut().take() else {
+                        return;
+                    };
+                    // Shown until the server sends the canvas back
+                    let mut painted = (*canvas).clone();
+                    s.paint_on(&mut painted);
+                    canvas.set(painted);
+                    let slow_down = slow_down.clone();
+                    spawn_local(async move {
+                        let resp = Request::post("/api/stroke")
+                            .json(&StrokePost {
+                                pixels: s.pixels,
+                                color: s.color,
+                                brush: s.brush,
+                                symmetry: s.symmetry,
+                                layer: s.layer,
+                                erase: s.erase,
+                            })
+                            .unwrap()
+                            .send()
+                            .await
+                            .unwrap();
+                        throttle::notice(resp.status(), &slow_down);
+                    });
+                })
+            };
+            let onpointerup = finish_stroke.clone();
+            // The stroke in progress drawn over the last canvas from the server
+            let shown = match &*stroke.borrow() {
+                Some(s) => {
+                    let mut shown = (*canvas).clone();
+                    s.paint_on(&mut shown);
+                    shown
+                }
+                None => (*canvas).clone(),
+            };
+            let cell = cell_size(&shown, *zoom);
+            {
+                let grid_ref = grid_ref.clone();
+                let palette = palette.clone();
+                let patterns = *patterns;
+                let shown = shown.clone();
+                use_effect(move || {
+                    if let Some(el) = grid_ref.cast::<HtmlCanvasElement>() {
+                        pixel::draw(
+                            &el,
+                            &shown,
+                            &palette,
+                            patterns,
+                            cell,
+                            &mut drawn.borrow_mut(),
+                        );
+                    }
+                });
+            }
+            let set_zoom = |step: isize| {
+                let zoom = zoom.clone();
+                let view_ref = view_ref.clone();
+                Callback::from(move |_| {
+                    let i = ZOOM_LEVELS.iter().position(|z| *z == *zoom).unwrap_or(0);
+                    let i = i.saturating_add_signed(step).min(ZOOM_LEVELS.len() - 1);
+                    // Keep the middle of the view where it was
+                    if let Some(view) = view_ref.cast::<Element>() {
+                        let ratio = ZOOM_LEVELS[i] as f64 / *zoom as f64;
+                        let (w, h) = (view.client_width() as f64, view.client_height() as f64);
+                        let left = (view.scroll_left() as f64 + w / 2.0) * ratio - w / 2.0;
+                        let top = (view.scroll_top() as f64 + h / 2.0) * ratio - h / 2.0;
+                        zoom.set(ZOOM_LEVELS[i]);
+                        let view = view.clone();
+                        // After the canvas got its new size
+                        gloo_timers::callback::Timeout::new(0, move || {
+                            view.set_scroll_left(left as i32);
+                            view.set_scroll_top(top as i32);
+                        })
+                        .forget();
+                    }
+                })
+            };
+            let zoom_out = set_zoom(-1);
+            let zoom_in = set_zoom(1);
             html! {
                 <div class={style}>
                     <div class={classes!("prompt", prompt_style)}>
@@ -412,112 +1218,475 @@ mod components {
                             }
                         }}
                     </div>
-                    <div class={classes!("canvas", canvas_style)}>{
-                        (0..*height)
-                        .map(|y| {
-                            (0..*width)
-                                .map(|x| {
-                                    let pos = y * *height + x;
+                    {if game_info.phase == GamePhase::Playing {
+                        html! {
+                            <div class={classes!("status", status_style.clone())}>
+                                {format!("Round {}", game_info.round)}
+                                {game_info.settings.rounds.map(|r| format!(" of {r}")).unwrap_or_default()}
+                                {game_info.time_left.map(|t| format!(" · {t}s left")).unwrap_or_default()}
+                            </div>
+                        }
+                    } else { html! {} }}
+                    if *slow_down {
+                        <div class={classes!("status", status_style)}>{"Slow down!"}</div>
+                    }
+                    <div class={view_style} ref={view_ref}>
+                        <canvas
+                            class={classes!("canvas", canvas_style)}
+                            ref={grid_ref}
+                            {onpointerdown}
+                            {onpointermove}
+                            {onpointerup}
+                            onpointercancel={finish_stroke}
+                        />
+                    </div>
+                    <div class={view_controls_style}>
+                        <button class={patterns_style.clone()} onclick={zoom_out}>{"−"}</button>
+                        <span>{format!("{}x", *zoom)}</span>
+                        <button class={patterns_style.clone()} onclick={zoom_in}>{"+"}</button>
+                    </div>
+                    if may_draw {
+                        <div class={classes!("controls", controls_style)}>
+                            {
+                                Tool::ALL.into_iter().map(|(t, name)| {
+                                    let selected = (*tool == t).then_some("selected");
+                                    let onclick = {
+                                        let tool = tool.clone();
+                                        Callback::from(move |_| tool.set(t))
+                                    };
+                                    html! {
+                                        <div {onclick} class={classes!("selectColor", "tool", selected)}>{ name }</div>
+                                    }
+                                }).collect::<Html>()
+                            }
+                            <div onclick={{
+                                let brush = brush.clone();
+                                Callback::from(move |_| brush.set(next(*brush)))
+                            }} class="selectColor tool" title="Brush size">{ brush.to_string() }</div>
+                            <div onclick={{
+                                let symmetry = symmetry.clone();
+                                Callback::from(move |_| symmetry.set(next(*symmetry)))
+                            }} class="selectColor tool" title="Symmetry">{ symmetry.to_string() }</div>
+                            if matches!(*tool, Tool::Rectangle | Tool::Ellipse) {
+                                <div onclick={{
+                                    let filled = filled.clone();
+                                    Callback::from(move |_| filled.set(!*filled))
+                                }} class="selectColor tool">{ if *filled { "Filled" } else { "Outline" } }</div>
+                            }
+                            {
+                                palette.indices().map(|c| {
+                                    let selected = (color == c).then_some("selected");
                                     let onclick = {
                                         let selected_color = selected_color.clone();
+                                        Callback::from(move |_| selected_color.set(c))
+                                    };
+                                    let rgb = palette.rgb(c);
+                                    html! {
+                                        <div {onclick}
+                                            class={classes!("selectColor", selected)}
+                                            style={format!(
+                                                "background-color: {}; color: {}",
+                                                rgb.css(),
+                                                if rgb.is_dark() { "white" } else { "black" },
+                                            )}
+                                            title={palette.get(c).map(|p| p.name.clone())}
+                                        >{ if *patterns { glyph(c) } else { String::new() } }</div>
+                                    }
+                                }).collect::<Html>()
+                            }
+                            <div onclick={{
+                                let slow_down = slow_down.clone();
+                                Callback::from(move |_| send_action("/api/undo", slow_down.clone()))
+                            }} class="selectColor tool" title="Ctrl+Z">{ "Undo" }</div>
+                            <div onclick={{
+                                let slow_down = slow_down.clone();
+                                Callback::from(move |_| send_action("/api/redo", slow_down.clone()))
+                            }} class="selectColor tool" title="Ctrl+Shift+Z">{ "Redo" }</div>
+                            <div onclick={{
+                                let slow_down = slow_down.clone();
+                                Callback::from(move |_| send_action("/api/clear_canvas", slow_down.clone()))
+                            }} class="selectColor tool">{ "Clear" }</div>
+                        </div>
+                    }
+                    if may_draw && !canvas.layers.is_empty() {
+                        <div class={layers_style}>
+                            {
+                                // Top layer first, like it is stacked
+                                canvas.layers.iter().enumerate().rev().map(|(i, l)| {
+                                    let count = canvas.layers.len();
+                                    let action = |change: fn(usize) -> LayerPost, to: Option<usize>| {
+                                        let selected_layer = selected_layer.clone();
+                                        let slow_down = slow_down.clone();
                                         Callback::from(move |_| {
-                                            let selected_color = selected_color.clone();
-                                            spawn_local(async move {
-                                                Request::post("/api/set_pixel")
-                                                    .json(&SetPixelPost { pixel_id: pos, color: *selected_color })
-                                                    .unwrap()
-                                                    .send()
-                                                    .await
-                                                    .unwrap();
-                                            });
+                                            // The selection follows the layer it was on
+                                            if let Some(to) = to.filter(|_| layer == i) {
+                                                selected_layer.set(to);
+                                            }
+                                            send_layers(change(i), slow_down.clone());
                                         })
                                     };
+                                    let visible = l.visible;
+                                    let toggle = {
+                                        let slow_down = slow_down.clone();
+                                        Callback::from(move |_| {
+                                            send_layers(
+                                                LayerPost::SetVisible { layer: i, visible: !visible },
+                                                slow_down.clone(),
+                                            )
+                                        })
+                                    };
+                                    let select = {
+                                        let selected_layer = selected_layer.clone();
+                                        Callback::from(move |_| selected_layer.set(i))
+                                    };
                                     html! {
-                                        <Pixel key={pos} color={grid[pos]} {onclick} />
+                                        <div class={classes!("layer", (layer == i).then_some("selected"), (!visible).then_some("hidden"))}>
+                                            <span class="name" onclick={select}>{ l.name.clone() }</span>
+                                            <button class={patterns_style.clone()} onclick={toggle}>
+                                                { if visible { "Hide" } else { "Show" } }
+                                            </button>
+                                            <button class={patterns_style.clone()} title="Move up" disabled={i + 1 == count}
+                                                onclick={action(|i| LayerPost::Move { from: i, to: i + 1 }, Some(i + 1))}>{ "▲" }</button>
+                                            <button class={patterns_style.clone()} title="Move down" disabled={i == 0}
+                                                onclick={action(|i| LayerPost::Move { from: i, to: i.saturating_sub(1) }, Some(i.saturating_sub(1)))}>{ "▼" }</button>
+                                            <button class={patterns_style.clone()} title="Remove" disabled={count == 1}
+                                                onclick={action(|i| LayerPost::Remove { layer: i }, None)}>{ "✕" }</button>
+                                        </div>
                                     }
+                                }).collect::<Html>()
+                            }
+                            <button class={patterns_style.clone()} disabled={canvas.layers.len() >= MAX_LAYERS} onclick={{
+                                let selected_layer = selected_layer.clone();
+                                let slow_down = slow_down.clone();
+                                let added = canvas.layers.len();
+                                Callback::from(move |_| {
+                                    // Draw on the new layer once it arrives
+                                    selected_layer.set(added);
+                                    send_layers(LayerPost::Add, slow_down.clone());
                                 })
-                                .collect::<Html>()
-                        })
-                        .collect::<Html>()
+                            }}>{ "Add layer" }</button>
+                        </div>
                     }
-                    </div>
-                    <div class={classes!("controls", controls_style)}>
-                        {
-                            Color::iter().map(|c| {
-                                let selected = if *selected_color == c {
-                                    Some("selected")} else { None
-                                };
-                                let onclick = {
-                                    let selected_color = selected_color.clone();
-                                    Callback::from(move |_| selected_color.set(c))
-                                };
-                                html! {
-                                    <div {onclick} class={classes!("selectColor", c.to_string().to_ascii_lowercase(), selected)}></div>
-                                }
-                            }).collect::<Html>()
-                        }
-                        <div onclick={{
-                            Callback::from(move |_| {
-                                spawn_local(async move {
-                                    Request::get("/api/clear_canvas")
-                                        .send()
-                                        .await
-                                        .unwrap();
-                                });
-                            })
-                        }} class="selectColor white">{ "Clear" }</div>
-                    </div>
+                    <button class={patterns_style} onclick={{
+                        let patterns = patterns.clone();
+                        Callback::from(move |_| {
+                            set_patterns_enabled(!*patterns);
+                            patterns.set(!*patterns);
+                        })
+                    }}>{ if *patterns { "Hide color patterns" } else { "Show color patterns" } }</button>
                 </div>
             }
         }
     }
-    pub mod pixel {
+    pub mod lobby {
+        use common::{GameInfo, GamePhase, Language, Palette, RoomSettings, WordPack};
+        use gloo_net::http::Request;
+        use strum::IntoEnumIterator;
         use stylist::yew::use_style;
+        use wasm_bindgen_futures::spawn_local;
+        use web_sys::{HtmlInputElement, HtmlSelectElement};
         use yew::prelude::*;
 
-        use common::Color;
-
-        #[derive(Properties, PartialEq)]
-        pub struct PixelProps {
-            pub color: Color,
-            pub onclick: Callback<()>,
+        /// Parses a limit input, where an empty field or zero means no limit
+        fn limit<T: std::str::FromStr + Default + PartialEq>(value: &str) -> Option<T> {
+            value.trim().parse().ok().filter(|n| *n != T::default())
+        }
+        fn limit_str<T: ToString>(value: Option<T>) -> String {
+            value.map(|n| n.to_string()).unwrap_or_default()
         }
-        #[function_component(Pixel)]
-        pub fn pixel(PixelProps { color, onclick }: &PixelProps) -> Html {
-            let onmousedown = {
-                let onclick = onclick.clone();
-                Callback::from(move |_| onclick.emit(()))
+        fn input(e: &Event) -> HtmlInputElement {
+            e.target_unchecked_into::<HtmlInputElement>()
+        }
+        fn select(e: &Event) -> String {
+            e.target_unchecked_into::<HtmlSelectElement>().value()
+        }
+        /// The server broadcasts the new settings back if they are accepted
+        fn post_settings(settings: RoomSettings) {
+            spawn_local(async move {
+                Request::post("/api/room_settings")
+                    .json(&settings)
+                    .unwrap()
+                    .send()
+                    .await
+                    .unwrap();
+            });
+        }
+
+        #[function_component(Lobby)]
+        pub fn lobby() -> Html {
+            let player = use_context::<Option<String>>().unwrap();
+            let game_info = use_context::<GameInfo>().unwrap();
+            let is_host = player.as_ref() == Some(&game_info.host);
+            let settings = game_info.settings;
+
+            let edit = |f: fn(&mut RoomSettings, &Event)| {
+                let settings = settings.clone();
+                Callback::from(move |e: Event| {
+                    let mut settings = settings.clone();
+                    f(&mut settings, &e);
+                    post_settings(settings);
+                })
             };
+            let start = Callback::from(move |_| {
+                spawn_local(async move {
+                    Request::post("/api/start_game").send().await.unwrap();
+                });
+            });
 
             let style = use_style!(
                 r#"
-                min-width: 10px;
-                min-height: 10px;
-                border: .5px solid #00000022;
-                cursor: crosshair;
+                flex: 0 0 245px;
+                display: flex;
+                flex-direction: column;
+                gap: 5px;
+                background-color: #6e7eef5e;
+                padding: 10px;
+                border-radius: 10px;
+                color: #eee;
+
+                & label {
+                    display: flex;
+                    justify-content: space-between;
+                    gap: 5px;
+                }
+                & input[type=number], & input[type=text], & select {
+                    width: 100px;
+                }
+                .palette {
+                    display: flex;
+                    flex-wrap: wrap;
+                    gap: 3px;
+                }
+                .palette > div {
+                    width: 25px;
+                    height: 25px;
+                    border-radius: 50%;
+                    border: 2px solid #eee;
+                }
             "#
             );
-
             html! {
-                <div
-                    class={classes!("pixel", style, format!("{:?}", *color).to_ascii_lowercase())}
-                    {onmousedown}
-                />
+                <div class={style}>
+                    <b>{if game_info.phase == GamePhase::Finished { "Game over!" } else { "Waiting for players..." }}</b>
+                    <div>{format!("Host: {}", game_info.host)}</div>
+                    <label>{"Canvas width"}
+                        <input type="number" min="4" max="64" disabled={!is_host}
+                            value={settings.canvas_width.to_string()}
+                            onchange={edit(|s, e| if let Ok(w) = input(e).value().parse() { s.canvas_width = w })} />
+                    </label>
+                    <label>{"Canvas height"}
+                        <input type="number" min="4" max="64" disabled={!is_host}
+                            value={settings.canvas_height.to_string()}
+                            onchange={edit(|s, e| if let Ok(h) = input(e).value().parse() { s.canvas_height = h })} />
+                    </label>
+                    <label>{"Seconds per turn"}
+                        <input type="number" min="0" placeholder="unlimited" disabled={!is_host}
+                            value={limit_str(settings.round_time)}
+                            onchange={edit(|s, e| s.round_time = limit(&input(e).value()))} />
+                    </label>
+                    <label>{"Rounds"}
+                        <input type="number" min="0" placeholder="unlimited" disabled={!is_host}
+                            value={limit_str(settings.rounds)}
+                            onchange={edit(|s, e| s.rounds = limit(&input(e).value()))} />
+                    </label>
+                    <label>{"Max players"}
+                        <input type="number" min="0" placeholder="unlimited" disabled={!is_host}
+                            value={limit_str(settings.max_players)}
+                            onchange={edit(|s, e| s.max_players = limit(&input(e).value()))} />
+                    </label>
+                    <label>{"Skip idle drawer after (s)"}
+                        <input type="number" min="0" placeholder="never" disabled={!is_host}
+                            value={limit_str(settings.afk_timeout)}
+                            onchange={edit(|s, e| s.afk_timeout = limit(&input(e).value()))} />
+                    </label>
+                    <label>{"Hints at (s)"}
+                        <input type="text" placeholder="e.g. 30, 60" disabled={!is_host}
+                            value={settings.hint_schedule.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ")}
+                            onchange={edit(|s, e| {
+                                s.hint_schedule = input(e).value().split(',').filter_map(|t| t.trim().parse().ok()).collect()
+                            })} />
+                    </label>
+                    <label>{"Words"}
+                        <select disabled={!is_host}
+                            onchange={edit(|s, e| {
+                                if let Some(w) = WordPack::iter().find(|w| w.to_string() == select(e)) { s.word_pack = w }
+                            })}>
+                            {WordPack::iter().map(|w| html! {
+                                <option selected={w == settings.word_pack}>{w.to_string()}</option>
+                            }).collect::<Html>()}
+                        </select>
+                    </label>
+                    <label>{"Language"}
+                        <select disabled={!is_host}
+                            onchange={edit(|s, e| {
+                                if let Some(l) = Language::iter().find(|l| l.to_string() == select(e)) { s.language = l }
+                            })}>
+                            {Language::iter().map(|l| html! {
+                                <option selected={l == settings.language}>{l.to_string()}</option>
+                            }).collect::<Html>()}
+                        </select>
+                    </label>
+                    <label>{"Public"}
+                        <input type="checkbox" disabled={!is_host} checked={settings.public}
+                            onchange={edit(|s, e| s.public = input(e).checked())} />
+                    </label>
+                    <label>{"Palette"}
+                        <select disabled={!is_host}
+                            onchange={edit(|s, e| {
+                                if let Some(p) = Palette::presets().into_iter().find(|p| p.name == select(e)) { s.palette = p }
+                            })}>
+                            {Palette::presets().into_iter().map(|p| html! {
+                                <option selected={p.name == settings.palette.name}>{p.name}</option>
+                            }).collect::<Html>()}
+                            // A custom palette set through the API
+                            if !Palette::presets().contains(&settings.palette) {
+                                <option selected=true>{settings.palette.name.clone()}</option>
+                            }
+                        </select>
+                    </label>
+                    <div class="palette">
+                        {
+                            settings.palette.colors.iter().map(|c| html! {
+                                <div title={c.name.clone()} style={format!("background-color: {}", c.rgb.css())}></div>
+                            }).collect::<Html>()
+                        }
+                    </div>
+                    {if is_host {
+                        html! {
+                            <button onclick={start}>
+                                {if game_info.phase == GamePhase::Finished { "Play again" } else { "Start game" }}
+                            </button>
+                        }
+                    } else { html! {} }}
+                </div>
+            }
+        }
+    }
+    pub mod pixel {
+        use common::{Color, DrawCanvas, Palette};
+        use wasm_bindgen::JsCast;
+        use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement};
+
+        const PATTERNS_KEY: &str = "pixel-skribbl-patterns";
+        const GLYPHS: [&str; 16] = [
+            "", "●", "■", "▲", "◆", "✚", "★", "♥", "✖", "▼", "◀", "▶", "○", "□", "△", "◇",
+        ];
+
+        /// Whether colors also get a glyph, remembered in local storage
+        pub fn patterns_enabled() -> bool {
+            web_sys::window()
+                .and_then(|w| w.local_storage().ok().flatten())
+                .and_then(|s| s.get_item(PATTERNS_KEY).ok().flatten())
+                .is_some_and(|v| v == "1")
+        }
+        pub fn set_patterns_enabled(enabled: bool) {
+            if let Some(s) = web_sys::window().and_then(|w| w.local_storage().ok().flatten()) {
+                let _ = s.set_item(PATTERNS_KEY, if enabled { "1" } else { "0" });
+            }
+        }
+        /// Tells palette colors apart without relying on hue. The background gets none
+        pub fn glyph(color: Color) -> String {
+            GLYPHS
+                .get(color.0 as usize)
+                .map_or_else(|| color.0.to_string(), |g| g.to_string())
+        }
+
+        /// What is on the `<canvas>` element, so that only changed pixels get redrawn
+        pub struct Drawn {
+            canvas: DrawCanvas,
+            palette: Palette,
+            patterns: bool,
+            cell: u32,
+        }
+
+        /// Draws the pixels that changed since `drawn`, or all of them when the size or
+        /// the look changed. Each pixel is `cell` by `cell` backing pixels
+        pub fn draw(
+            el: &HtmlCanvasElement,
+            canvas: &DrawCanvas,
+            palette: &Palette,
+            patterns: bool,
+            cell: u32,
+            drawn: &mut Option<Drawn>,
+        ) {
+            let full = !drawn.as_ref().is_some_and(|d| {
+                d.canvas.width == canvas.width
+                    && d.canvas.height == canvas.height
+                    && d.palette == *palette
+                    && d.patterns == patterns
+                    && d.cell == cell
+            });
+            if full {
+                el.set_width(canvas.width as u32 * cell);
+                el.set_height(canvas.height as u32 * cell);
+            }
+            let Some(ctx) = el
+                .get_context("2d")
+                .ok()
+                .flatten()
+                .and_then(|c| c.dyn_into::<CanvasRenderingContext2d>().ok())
+            else {
+                return;
+            };
+            for y in 0..canvas.height {
+                for x in 0..canvas.width {
+                    let color = canvas.get_pixel(x, y).unwrap_or_default();
+                    let old = drawn.as_ref().and_then(|d| d.canvas.get_pixel(x, y));
+                    if full || old != Some(color) {
+                        draw_pixel(&ctx, (x, y), color, palette, patterns, cell);
+                    }
+                }
+            }
+            *drawn = Some(Drawn {
+                canvas: canvas.clone(),
+                palette: palette.clone(),
+                patterns,
+                cell,
+            });
+        }
+
+        fn draw_pixel(
+            ctx: &CanvasRenderingContext2d,
+            (x, y): (usize, usize),
+            color: Color,
+            palette: &Palette,
+            patterns: bool,
+            cell: u32,
+        ) {
+            let (left, top, size) = (
+                (x as u32 * cell) as f64,
+                (y as u32 * cell) as f64,
+                cell as f64,
+            );
+            let rgb = palette.rgb(color);
+            ctx.set_fill_style_str(&rgb.css());
+            ctx.fill_rect(left, top, size, size);
+            // Faint grid lines, once pixels are big enough for them
+            if cell >= 6 {
+                ctx.set_fill_style_str("#00000022");
+                ctx.fill_rect(left + size - 1.0, top, 1.0, size);
+                ctx.fill_rect(left, top + size - 1.0, size, 1.0);
+            }
+            let glyph = glyph(color);
+            if patterns && !glyph.is_empty() {
+                let text = if rgb.is_dark() { "white" } else { "black" };
+                ctx.set_fill_style_str(text);
+                ctx.set_font(&format!("{}px sans-serif", (size * 0.7) as u32));
+                ctx.set_text_align("center");
+                ctx.set_text_baseline("middle");
+                let _ = ctx.fill_text(&glyph, left + size / 2.0, top + size / 2.0);
             }
         }
     }
     pub mod chat {
+        use super::{socket::subscribe, throttle};
         use bounded_vec_deque::BoundedVecDeque;
         use common::{ChatMessage, GameInfo};
-        use futures::StreamExt;
-        use gloo_net::{
-            http::Request,
-            websocket::{futures::WebSocket, Message},
-        };
+        use gloo_net::http::Request;
         use stylist::yew::use_style;
         use wasm_bindgen::JsCast;
         use wasm_bindgen_futures::spawn_local;
-        use web_sys::{console, HtmlInputElement};
+        use web_sys::HtmlInputElement;
         use yew::prelude::*;
         #[derive(PartialEq, Properties)]
         pub struct ChatProps {}
@@ -527,9 +1696,11 @@ mod components {
             let messages = use_mut_ref(|| BoundedVecDeque::<ChatMessage>::new(50));
             let messages_update = use_force_update();
             let text = use_state(String::new);
+            let slow_down = use_state_eq(|| false);
             let game_info = use_context::<GameInfo>().unwrap();
             let room_id = game_info.room_id;
             let players = game_info.players;
+            let spectators = game_info.spectators;
             let onchange = {
                 let text = text.clone();
                 Callback::from(move |e: Event| {
@@ -544,12 +1715,14 @@ mod components {
             };
             let onsubmit = {
                 let text = text.clone();
+                let slow_down = slow_down.clone();
                 Callback::from(move |e: SubmitEvent| {
                     e.prevent_default();
                     let text = text.clone();
+                    let slow_down = slow_down.clone();
                     if !text.is_empty() {
                         spawn_local(async move {
-                            Request::post("/api/chat")
+                            let resp = Request::post("/api/chat")
                                 .json(&ChatMessage {
                                     username: "".into(),
                                     text: (*text).clone(),
@@ -558,7 +1731,11 @@ mod components {
                                 .send()
                                 .await
                                 .unwrap();
-                            text.set(String::new());
+                            throttle::notice(resp.status(), &slow_down);
+                            // Keep the message around to send again after slowing down
+                            if resp.ok() {
+                                text.set(String::new());
+                            }
                         });
                     }
                 })
@@ -568,25 +1745,15 @@ mod components {
                     let messages = messages.clone();
                     let room_id = room_id.clone();
                     move |_| {
-                        let host = web_sys::window().unwrap().location().host().unwrap();
-                        let secure =
-                            web_sys::window().unwrap().location().protocol().unwrap() == "https:";
-                        let ws = WebSocket::open(&format!(
-                            "ws{}://{host}/ws/{room_id}/chat",
-                            if secure { "s" } else { "" }
-                        ))
-                        .unwrap();
-                        let (mut _write, mut read) = ws.split();
-                        let f = async move {
-                            while let Some(Ok(Message::Text(msg))) = read.next().await {
-                                console::log_1(&format!("Received on Chat {:?}", msg).into());
+                        subscribe(
+                            room_id,
+                            "chat",
+                            Callback::from(move |msg: String| {
                                 let cm: ChatMessage = serde_json::from_str(&msg).unwrap();
                                 (*messages).borrow_mut().push_back(cm);
                                 messages_update.force_update();
-                            }
-                            console::log_1(&"Chat WebSocket Closed".into());
-                        };
-                        spawn_local(f);
+                            }),
+                        );
                     }
                 },
                 (),
@@ -634,10 +1801,27 @@ mod components {
                                 players.len(),
                                 players
                                     .into_iter()
-                                    .map(|p| if p.active { format!("{} (drawing)", p.username) } else { p.username })
+                                    .map(|p| match (p.active, p.skipped_turns > 0) {
+                                        (true, _) => format!("{} (drawing)", p.username),
+                                        (false, true) => format!("{} (away)", p.username),
+                                        (false, false) => p.username,
+                                    })
                                     .collect::<Vec<String>>()
                                     .join(", "))
                         }
+                        if !spectators.is_empty() {
+                            <br />
+                            {
+                                format!(
+                                    "Spectators ({}): {}",
+                                    spectators.len(),
+                                    spectators
+                                        .into_iter()
+                                        .map(|s| if s.queued { format!("{} (waiting)", s.username) } else { s.username })
+                                        .collect::<Vec<String>>()
+                                        .join(", "))
+                            }
+                        }
                     </div>
                     <div class={chat_style}>
                         {
@@ -653,6 +1837,9 @@ mod components {
                         }
                     </div>
                     <div>
+                        if *slow_down {
+                            <div>{"Slow down!"}</div>
+                        }
                         <form {onsubmit}>
                             <input type="text" value={(*text).clone()} {onchange} />
                             <input type="submit" value="Send" />